@@ -0,0 +1,107 @@
+use std::sync::Arc;
+
+use crate::prelude::*;
+
+/// A request to derive keys at `derivation_paths` using a single factor source.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct MonoFactorKeyDerivationRequest {
+    pub factor_source: HDFactorSource,
+    pub derivation_paths: IndexSet<DerivationPath>,
+}
+impl MonoFactorKeyDerivationRequest {
+    pub fn new(factor_source: HDFactorSource, derivation_paths: IndexSet<DerivationPath>) -> Self {
+        Self {
+            factor_source,
+            derivation_paths,
+        }
+    }
+}
+
+/// A request to derive keys using many factor sources of the same kind at once.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct PolyFactorKeyDerivationRequest {
+    pub kind: FactorSourceKind,
+    pub per_factor_source: IndexMap<FactorSourceID, MonoFactorKeyDerivationRequest>,
+}
+impl PolyFactorKeyDerivationRequest {
+    /// Only built by the `KeysCollector`, which groups the requests per kind,
+    /// so every factor source in `per_factor_source` is of `kind`.
+    pub(crate) fn new(
+        kind: FactorSourceKind,
+        per_factor_source: IndexMap<FactorSourceID, MonoFactorKeyDerivationRequest>,
+    ) -> Self {
+        Self {
+            kind,
+            per_factor_source,
+        }
+    }
+}
+
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct KeyDerivationResponse {
+    pub per_factor_source: IndexMap<FactorSourceID, IndexSet<HDFactorInstance>>,
+
+    /// Factor sources the user chose not to use, must be disjoint
+    /// with the keys of `per_factor_source`.
+    pub skipped: IndexSet<FactorSourceID>,
+}
+impl KeyDerivationResponse {
+    pub fn new(per_factor_source: IndexMap<FactorSourceID, IndexSet<HDFactorInstance>>) -> Self {
+        Self {
+            per_factor_source,
+            skipped: IndexSet::new(),
+        }
+    }
+    pub fn skipping(skipped: IndexSet<FactorSourceID>) -> Self {
+        Self {
+            per_factor_source: IndexMap::new(),
+            skipped,
+        }
+    }
+}
+
+/// Derives keys for many factor sources in one interaction, e.g. all
+/// device mnemonics, which requires no extra user interaction per factor.
+#[async_trait::async_trait]
+pub trait PolyFactorKeyDerivationInteractor: Send + Sync {
+    async fn derive(
+        &self,
+        request: PolyFactorKeyDerivationRequest,
+    ) -> Result<KeyDerivationResponse>;
+}
+
+/// Derives keys for one factor source at a time, e.g. a Ledger device which
+/// the user must connect, one after the other.
+#[async_trait::async_trait]
+pub trait MonoFactorKeyDerivationInteractor: Send + Sync {
+    async fn derive(
+        &self,
+        request: MonoFactorKeyDerivationRequest,
+    ) -> Result<KeyDerivationResponse>;
+}
+
+#[derive(Clone)]
+pub enum KeyDerivationInteractor {
+    /// Derives for all factor sources of a kind at once.
+    Parallel(Arc<dyn PolyFactorKeyDerivationInteractor>),
+    /// Derives for one factor source at a time.
+    Serial(Arc<dyn MonoFactorKeyDerivationInteractor>),
+}
+
+/// A registry of interactors, one per `FactorSourceKind`, used by the
+/// `KeysCollector` to derive keys.
+#[derive(Clone, Default)]
+pub struct KeysDerivationInteractors {
+    per_kind: IndexMap<FactorSourceKind, KeyDerivationInteractor>,
+}
+impl KeysDerivationInteractors {
+    pub fn new(per_kind: IndexMap<FactorSourceKind, KeyDerivationInteractor>) -> Self {
+        Self { per_kind }
+    }
+    pub fn interactor_for(&self, kind: FactorSourceKind) -> Result<KeyDerivationInteractor> {
+        self.per_kind
+            .get(&kind)
+            .cloned()
+            .ok_or(CommonError::NoInteractorForFactorSourceKind { kind })
+    }
+}
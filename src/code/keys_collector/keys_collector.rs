@@ -1,20 +1,451 @@
 use crate::prelude::*;
 
-pub struct KeysCollector;
+/// Derives keys for many factor sources at once, one `HDFactorInstance` per
+/// requested `DerivationPath`, using the interactor registered for the kind
+/// of each factor source.
+pub struct KeysCollector {
+    factors: IndexMap<FactorSourceID, HDFactorSource>,
+    derivation_paths: IndexMap<FactorSourceID, IndexSet<DerivationPath>>,
+    interactors: KeysDerivationInteractors,
+}
 impl KeysCollector {
     pub fn new(
         factors: IndexSet<HDFactorSource>,
         derivation_paths: IndexMap<FactorSourceID, IndexSet<DerivationPath>>,
-    ) -> Self {
-        todo!()
+        interactors: KeysDerivationInteractors,
+    ) -> Result<Self> {
+        let factors = factors
+            .into_iter()
+            .map(|f| (f.factor_source_id, f))
+            .collect::<IndexMap<_, _>>();
+
+        if let Some(id) = derivation_paths
+            .keys()
+            .find(|id| !factors.contains_key(*id))
+        {
+            return Err(CommonError::UnknownFactorSource {
+                factor_source_id: *id,
+            });
+        }
+
+        Ok(Self {
+            factors,
+            derivation_paths,
+            interactors,
+        })
+    }
+
+    /// The requests to dispatch, grouped by `FactorSourceKind`, in the order
+    /// of `FactorSourceKind`.
+    fn requests_per_kind(
+        &self,
+    ) -> IndexMap<FactorSourceKind, IndexMap<FactorSourceID, MonoFactorKeyDerivationRequest>> {
+        self.derivation_paths
+            .iter()
+            .map(|(id, paths)| {
+                let factor_source = self.factors.get(id).expect("Validated in `new`").clone();
+                (
+                    id.kind,
+                    (
+                        *id,
+                        MonoFactorKeyDerivationRequest::new(factor_source, paths.clone()),
+                    ),
+                )
+            })
+            .sorted_by_key(|(kind, _)| *kind)
+            .into_group_map()
+            .into_iter()
+            .sorted_by_key(|(kind, _)| *kind)
+            .map(|(kind, requests)| (kind, requests.into_iter().collect()))
+            .collect()
+    }
+
+    /// Each instance must be for a requested factor source and path, every
+    /// requested path must have been derived, unless the user skipped the
+    /// factor source. Fails naming the first factor source which is not.
+    fn validate(
+        &self,
+        requests: &IndexMap<FactorSourceID, MonoFactorKeyDerivationRequest>,
+        response: &KeyDerivationResponse,
+    ) -> Result<()> {
+        let invalid = response
+            .per_factor_source
+            .keys()
+            .chain(response.skipped.iter())
+            .find(|id| !requests.contains_key(*id))
+            .or_else(|| {
+                requests
+                    .iter()
+                    .find(|(id, request)| match response.per_factor_source.get(*id) {
+                        Some(instances) => {
+                            response.skipped.contains(*id)
+                                || !instances.iter().all(|f| f.factor_source_id == **id)
+                                || instances
+                                    .iter()
+                                    .map(|f| f.derivation_path)
+                                    .collect::<IndexSet<_>>()
+                                    != request.derivation_paths
+                        }
+                        None => !response.skipped.contains(*id),
+                    })
+                    .map(|(id, _)| id)
+            });
+        match invalid {
+            Some(id) => Err(CommonError::InvalidDerivationResponse {
+                factor_source_id: *id,
+            }),
+            None => Ok(()),
+        }
     }
-    #[allow(unused)]
+
+    /// Translates the `response` (or error) of an interactor into an outcome
+    /// for each factor source in `requests`.
+    fn outcomes(
+        &self,
+        requests: &IndexMap<FactorSourceID, MonoFactorKeyDerivationRequest>,
+        response: Result<KeyDerivationResponse>,
+    ) -> IndexMap<FactorSourceID, FactorSourceDerivationOutcome> {
+        match response.and_then(|r| self.validate(requests, &r).map(|_| r)) {
+            Ok(response) => requests
+                .keys()
+                .map(|id| {
+                    let outcome = response
+                        .per_factor_source
+                        .get(id)
+                        .cloned()
+                        .map(FactorSourceDerivationOutcome::Derived)
+                        .unwrap_or(FactorSourceDerivationOutcome::Skipped);
+                    (*id, outcome)
+                })
+                .collect(),
+            Err(error) => requests
+                .keys()
+                .map(|id| (*id, FactorSourceDerivationOutcome::Failed(error.clone())))
+                .collect(),
+        }
+    }
+
+    async fn dispatch(
+        &self,
+        kind: FactorSourceKind,
+        requests: IndexMap<FactorSourceID, MonoFactorKeyDerivationRequest>,
+    ) -> IndexMap<FactorSourceID, FactorSourceDerivationOutcome> {
+        let interactor = match self.interactors.interactor_for(kind) {
+            Ok(interactor) => interactor,
+            Err(error) => return self.outcomes(&requests, Err(error)),
+        };
+        match interactor {
+            KeyDerivationInteractor::Parallel(interactor) => {
+                let response = interactor
+                    .derive(PolyFactorKeyDerivationRequest::new(kind, requests.clone()))
+                    .await;
+                self.outcomes(&requests, response)
+            }
+            KeyDerivationInteractor::Serial(interactor) => {
+                let mut outcomes = IndexMap::new();
+                for (id, request) in requests {
+                    let response = interactor.derive(request.clone()).await;
+                    outcomes.extend(self.outcomes(&IndexMap::from_iter([(id, request)]), response));
+                }
+                outcomes
+            }
+        }
+    }
+
+    /// Never fails as a whole, instead the outcome of each factor source is
+    /// reported, the caller decides if the outcome is good enough.
     pub async fn collect_keys(self) -> KeyDerivationOutcome {
-        todo!()
+        let mut per_factor_source = IndexMap::new();
+        for (kind, requests) in self.requests_per_kind() {
+            per_factor_source.extend(self.dispatch(kind, requests).await);
+        }
+        KeyDerivationOutcome::new(per_factor_source)
     }
 }
 
+/// The outcome of key derivation for a single factor source.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum FactorSourceDerivationOutcome {
+    /// All requested keys were derived.
+    Derived(IndexSet<HDFactorInstance>),
+
+    /// The user chose not to use this factor source, e.g. did not have
+    /// the Ledger device at hand.
+    Skipped,
+
+    /// The interactor failed to derive keys for this factor source.
+    Failed(CommonError),
+}
+
 #[derive(Clone, Debug, PartialEq, Eq)]
 pub struct KeyDerivationOutcome {
-    instances: IndexSet<HDFactorInstance>,
+    per_factor_source: IndexMap<FactorSourceID, FactorSourceDerivationOutcome>,
+}
+impl KeyDerivationOutcome {
+    pub fn new(per_factor_source: IndexMap<FactorSourceID, FactorSourceDerivationOutcome>) -> Self {
+        Self { per_factor_source }
+    }
+    pub fn outcome_for(
+        &self,
+        factor_source_id: FactorSourceID,
+    ) -> Option<&FactorSourceDerivationOutcome> {
+        self.per_factor_source.get(&factor_source_id)
+    }
+
+    /// All instances of all factor sources which were derived
+    pub fn all_instances(&self) -> IndexSet<HDFactorInstance> {
+        self.per_factor_source
+            .keys()
+            .flat_map(|id| self.instances_for_factor_source(*id))
+            .collect()
+    }
+    pub fn instances_for_factor_source(
+        &self,
+        factor_source_id: FactorSourceID,
+    ) -> IndexSet<HDFactorInstance> {
+        match self.outcome_for(factor_source_id) {
+            Some(FactorSourceDerivationOutcome::Derived(instances)) => instances.clone(),
+            _ => IndexSet::new(),
+        }
+    }
+
+    /// Succeeds if every factor source in `required` was derived, else the
+    /// error for the first one which was not.
+    pub fn ensure_derived(&self, required: &IndexSet<FactorSourceID>) -> Result<()> {
+        required.iter().try_for_each(|id| {
+            let factor_source_id = *id;
+            match self.outcome_for(factor_source_id) {
+                Some(FactorSourceDerivationOutcome::Derived(_)) => Ok(()),
+                Some(FactorSourceDerivationOutcome::Skipped) => {
+                    Err(CommonError::FactorSourceSkippedByUser { factor_source_id })
+                }
+                Some(FactorSourceDerivationOutcome::Failed(underlying)) => {
+                    Err(CommonError::FactorSourceDerivationFailed {
+                        factor_source_id,
+                        underlying: Box::new(underlying.clone()),
+                    })
+                }
+                None => Err(CommonError::UnknownFactorSource { factor_source_id }),
+            }
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+
+    use super::*;
+
+    type Sut = KeysCollector;
+
+    fn paths(
+        network_id: NetworkID,
+        indices: impl IntoIterator<Item = u32>,
+    ) -> IndexSet<DerivationPath> {
+        indices
+            .into_iter()
+            .map(|i| {
+                DerivationPath::new(
+                    network_id,
+                    CAP26EntityKind::Account,
+                    CAP26KeyKind::TransactionSigning,
+                    CAP26EntityIndex::Unsecurified(i),
+                )
+            })
+            .collect()
+    }
+
+    #[actix::test]
+    async fn one_instance_per_requested_path() {
+        let bdfs = HDFactorSource::sample();
+        let other = HDFactorSource::sample_other();
+        let sut = Sut::new(
+            IndexSet::from_iter([bdfs.clone(), other.clone()]),
+            IndexMap::from_iter([
+                (bdfs.factor_source_id, paths(NetworkID::Mainnet, 0..3)),
+                (other.factor_source_id, paths(NetworkID::Mainnet, 0..2)),
+            ]),
+            KeysDerivationInteractors::test(),
+        )
+        .unwrap();
+
+        let outcome = sut.collect_keys().await;
+
+        assert_eq!(outcome.all_instances().len(), 5);
+        assert_eq!(
+            outcome
+                .instances_for_factor_source(bdfs.factor_source_id)
+                .into_iter()
+                .map(|f| f.derivation_path)
+                .collect::<IndexSet<_>>(),
+            paths(NetworkID::Mainnet, 0..3)
+        );
+        assert_eq!(
+            outcome
+                .all_instances()
+                .into_iter()
+                .map(|f| f.public_key)
+                .collect::<IndexSet<_>>()
+                .len(),
+            5
+        );
+    }
+
+    #[actix::test]
+    async fn derivation_is_deterministic() {
+        let bdfs = HDFactorSource::sample();
+        let collect = || async {
+            Sut::new(
+                IndexSet::from_iter([bdfs.clone()]),
+                IndexMap::from_iter([(bdfs.factor_source_id, paths(NetworkID::Testnet, 0..2))]),
+                KeysDerivationInteractors::test(),
+            )
+            .unwrap()
+            .collect_keys()
+            .await
+        };
+        assert_eq!(collect().await, collect().await);
+    }
+
+    #[test]
+    fn unknown_factor_source_is_err() {
+        let bdfs = HDFactorSource::sample();
+        assert_eq!(
+            Sut::new(
+                IndexSet::from_iter([bdfs]),
+                IndexMap::from_iter([(
+                    FactorSourceID::sample_other(),
+                    paths(NetworkID::Mainnet, 0..1)
+                )]),
+                KeysDerivationInteractors::test(),
+            )
+            .err(),
+            Some(CommonError::UnknownFactorSource {
+                factor_source_id: FactorSourceID::sample_other()
+            })
+        );
+    }
+
+    #[actix::test]
+    async fn missing_interactor_is_failure() {
+        let ledger = HDFactorSource::sample_other();
+        let sut = Sut::new(
+            IndexSet::from_iter([ledger.clone()]),
+            IndexMap::from_iter([(ledger.factor_source_id, paths(NetworkID::Mainnet, 0..1))]),
+            KeysDerivationInteractors::default(),
+        )
+        .unwrap();
+        let outcome = sut.collect_keys().await;
+        let error = CommonError::NoInteractorForFactorSourceKind {
+            kind: FactorSourceKind::Ledger,
+        };
+        assert_eq!(
+            outcome.outcome_for(ledger.factor_source_id),
+            Some(&FactorSourceDerivationOutcome::Failed(error.clone()))
+        );
+        assert_eq!(
+            outcome.ensure_derived(&IndexSet::from_iter([ledger.factor_source_id])),
+            Err(CommonError::FactorSourceDerivationFailed {
+                factor_source_id: ledger.factor_source_id,
+                underlying: Box::new(error),
+            })
+        );
+    }
+
+    struct WrongPathInteractor;
+    #[async_trait::async_trait]
+    impl MonoFactorKeyDerivationInteractor for WrongPathInteractor {
+        async fn derive(
+            &self,
+            request: MonoFactorKeyDerivationRequest,
+        ) -> Result<KeyDerivationResponse> {
+            let wrong_paths = paths(NetworkID::Testnet, 0..request.derivation_paths.len() as u32);
+            Ok(KeyDerivationResponse::new(IndexMap::from_iter([(
+                request.factor_source.factor_source_id,
+                request.factor_source.derive(&wrong_paths)?,
+            )])))
+        }
+    }
+
+    #[actix::test]
+    async fn response_for_other_paths_is_failure() {
+        let ledger = HDFactorSource::sample_other();
+        let sut = Sut::new(
+            IndexSet::from_iter([ledger.clone()]),
+            IndexMap::from_iter([(ledger.factor_source_id, paths(NetworkID::Mainnet, 0..2))]),
+            KeysDerivationInteractors::new(IndexMap::from_iter([(
+                FactorSourceKind::Ledger,
+                KeyDerivationInteractor::Serial(std::sync::Arc::new(WrongPathInteractor)),
+            )])),
+        )
+        .unwrap();
+        assert_eq!(
+            sut.collect_keys()
+                .await
+                .outcome_for(ledger.factor_source_id),
+            Some(&FactorSourceDerivationOutcome::Failed(
+                CommonError::InvalidDerivationResponse {
+                    factor_source_id: ledger.factor_source_id
+                }
+            ))
+        );
+    }
+
+    struct SkippingInteractor;
+    #[async_trait::async_trait]
+    impl MonoFactorKeyDerivationInteractor for SkippingInteractor {
+        async fn derive(
+            &self,
+            request: MonoFactorKeyDerivationRequest,
+        ) -> Result<KeyDerivationResponse> {
+            Ok(KeyDerivationResponse::skipping(IndexSet::from_iter([
+                request.factor_source.factor_source_id,
+            ])))
+        }
+    }
+
+    #[actix::test]
+    async fn skipped_ledger_does_not_affect_device() {
+        let bdfs = HDFactorSource::sample();
+        let ledger = HDFactorSource::sample_other();
+        let mut interactors = IndexMap::from_iter([(
+            FactorSourceKind::Ledger,
+            KeyDerivationInteractor::Serial(std::sync::Arc::new(SkippingInteractor)),
+        )]);
+        interactors.insert(
+            FactorSourceKind::Device,
+            KeysDerivationInteractors::test()
+                .interactor_for(FactorSourceKind::Device)
+                .unwrap(),
+        );
+        let sut = Sut::new(
+            IndexSet::from_iter([bdfs.clone(), ledger.clone()]),
+            IndexMap::from_iter([
+                (bdfs.factor_source_id, paths(NetworkID::Mainnet, 0..2)),
+                (ledger.factor_source_id, paths(NetworkID::Mainnet, 0..2)),
+            ]),
+            KeysDerivationInteractors::new(interactors),
+        )
+        .unwrap();
+
+        let outcome = sut.collect_keys().await;
+
+        assert_eq!(
+            outcome.outcome_for(ledger.factor_source_id),
+            Some(&FactorSourceDerivationOutcome::Skipped)
+        );
+        assert_eq!(outcome.all_instances().len(), 2);
+        assert!(outcome
+            .ensure_derived(&IndexSet::from_iter([bdfs.factor_source_id]))
+            .is_ok());
+        assert_eq!(
+            outcome.ensure_derived(&IndexSet::from_iter([
+                bdfs.factor_source_id,
+                ledger.factor_source_id
+            ])),
+            Err(CommonError::FactorSourceSkippedByUser {
+                factor_source_id: ledger.factor_source_id
+            })
+        );
+    }
 }
@@ -1,4 +1,8 @@
+mod derivation_interactors;
 #[allow(clippy::module_inception)]
 mod keys_collector;
+mod test_derivation_interactor;
 
+pub use derivation_interactors::*;
 pub use keys_collector::*;
+pub use test_derivation_interactor::*;
@@ -0,0 +1,67 @@
+use std::sync::Arc;
+
+use crate::prelude::*;
+
+/// An in-memory interactor which derives keys using the mnemonic of the
+/// factor source, without any user interaction or hardware.
+#[derive(Clone, Debug, Default)]
+pub struct TestDerivationInteractor;
+
+impl TestDerivationInteractor {
+    fn derive_mono(request: MonoFactorKeyDerivationRequest) -> Result<KeyDerivationResponse> {
+        Ok(KeyDerivationResponse::new(IndexMap::from_iter([(
+            request.factor_source.factor_source_id,
+            request.factor_source.derive(&request.derivation_paths)?,
+        )])))
+    }
+}
+
+#[async_trait::async_trait]
+impl PolyFactorKeyDerivationInteractor for TestDerivationInteractor {
+    async fn derive(
+        &self,
+        request: PolyFactorKeyDerivationRequest,
+    ) -> Result<KeyDerivationResponse> {
+        let mut per_factor_source = IndexMap::new();
+        for r in request.per_factor_source.into_values() {
+            per_factor_source.extend(Self::derive_mono(r)?.per_factor_source);
+        }
+        Ok(KeyDerivationResponse::new(per_factor_source))
+    }
+}
+
+#[async_trait::async_trait]
+impl MonoFactorKeyDerivationInteractor for TestDerivationInteractor {
+    async fn derive(
+        &self,
+        request: MonoFactorKeyDerivationRequest,
+    ) -> Result<KeyDerivationResponse> {
+        Self::derive_mono(request)
+    }
+}
+
+impl KeysDerivationInteractors {
+    /// Uses `TestDerivationInteractor` for every kind, `Device` in parallel
+    /// and all others serially - like a real app would.
+    pub fn test() -> Self {
+        let interactor = Arc::new(TestDerivationInteractor);
+        Self::new(IndexMap::from_iter([
+            (
+                FactorSourceKind::Ledger,
+                KeyDerivationInteractor::Serial(interactor.clone()),
+            ),
+            (
+                FactorSourceKind::Arculus,
+                KeyDerivationInteractor::Serial(interactor.clone()),
+            ),
+            (
+                FactorSourceKind::OffDeviceMnemonic,
+                KeyDerivationInteractor::Serial(interactor.clone()),
+            ),
+            (
+                FactorSourceKind::Device,
+                KeyDerivationInteractor::Parallel(interactor),
+            ),
+        ]))
+    }
+}
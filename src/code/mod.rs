@@ -2,8 +2,10 @@ mod keys_collector;
 mod new_types;
 mod provider;
 mod sargon;
+mod storage;
 
 pub use keys_collector::*;
 pub use new_types::*;
 pub use provider::*;
 pub use sargon::*;
+pub use storage::*;
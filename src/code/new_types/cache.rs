@@ -2,29 +2,342 @@ use std::sync::RwLock;
 
 use crate::prelude::*;
 
+/// The indices, per factor source and template, reserved by a cache since it
+/// was stamped with its version.
+type Reservations = IndexMap<(FactorSourceID, DerivationTemplate), IndexSet<CAP26EntityIndex>>;
+
+/// The number of merges whose reservations a cache remembers, a snapshot
+/// older than that cannot be rebased.
+const MERGE_HISTORY_LENGTH: usize = 32;
+
+/// Instances consumed from and appended to a cache since it was stamped
+/// with its version, used to rebase it onto a newer version.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+struct CacheChanges {
+    consumed: IndexSet<HDFactorInstance>,
+    appended: IndexSet<HDFactorInstance>,
+    /// Every index derived - cached or used directly - or consumed, never
+    /// undone by putting instances back.
+    reserved: Reservations,
+}
+impl CacheChanges {
+    fn reserve<'a>(&mut self, instances: impl IntoIterator<Item = &'a HDFactorInstance>) {
+        for instance in instances {
+            let path = instance.derivation_path;
+            if let Some(template) = DerivationTemplate::all()
+                .into_iter()
+                .find(|t| t.matches(&path))
+            {
+                self.reserved
+                    .entry((instance.factor_source_id, template))
+                    .or_default()
+                    .insert(path.entity_index);
+            }
+        }
+    }
+
+    fn overlaps(&self, reserved: &Reservations) -> bool {
+        self.reserved.iter().any(|(key, indices)| {
+            reserved
+                .get(key)
+                .is_some_and(|other| !other.is_disjoint(indices))
+        })
+    }
+}
+
 /// On one specific network
 #[derive(Debug)]
 pub struct FactorInstancesForSpecificNetworkCache {
+    #[allow(dead_code)]
     hidden_constructor: HiddenConstructor,
     pub network_id: NetworkID,
+    /// The version of the cache this is - or is a snapshot of - bumped
+    /// by `FactorInstancesForEachNetworkCache::merge`.
+    version: u64,
     per_factor_source: RwLock<IndexMap<FactorSourceID, CollectionsOfFactorInstances>>,
+    changes: RwLock<CacheChanges>,
+    /// The reservations of the latest merges, oldest first, each with the
+    /// version it resulted in.
+    merge_history: Vec<(u64, Reservations)>,
 }
 impl FactorInstancesForSpecificNetworkCache {
     pub fn cloned_snapshot(&self) -> Self {
         Self {
             hidden_constructor: HiddenConstructor,
             network_id: self.network_id,
+            version: self.version,
             per_factor_source: RwLock::new(self.per_factor_source.read().unwrap().clone()),
+            changes: RwLock::new(self.changes.read().unwrap().clone()),
+            merge_history: self.merge_history.clone(),
+        }
+    }
+
+    pub fn version(&self) -> u64 {
+        self.version
+    }
+
+    /// Stamps self with `version`, forgetting all changes made so far.
+    pub fn with_version(self, version: u64) -> Self {
+        Self {
+            version,
+            changes: RwLock::new(CacheChanges::default()),
+            ..self
+        }
+    }
+
+    /// Returns `instances` - provided but not used after all, e.g. since the
+    /// user rejected the transaction securifying the accounts - to the set of
+    /// their template, in index order.
+    ///
+    /// Fails without returning any instance if any of them is used in
+    /// `profile`, is not on the network of self or is not valid for any
+    /// template.
+    pub fn return_instances(
+        &self,
+        instances: IndexSet<HDFactorInstance>,
+        profile: &Profile,
+    ) -> Result<()> {
+        let used = profile
+            .all_factor_instances()
+            .into_iter()
+            .map(|f| (f.factor_source_id, f.derivation_path))
+            .collect::<IndexSet<_>>();
+        if instances
+            .iter()
+            .any(|f| used.contains(&(f.factor_source_id, f.derivation_path)))
+        {
+            return Err(CommonError::InstanceUsedInProfile);
+        }
+        self.put_back(instances)
+    }
+
+    /// Puts `instances` back into the set of their template, in index order,
+    /// e.g. instances which were consumed but ended up not being used.
+    pub fn put_back(&self, instances: IndexSet<HDFactorInstance>) -> Result<()> {
+        if !instances.iter().all(|f| {
+            DerivationTemplate::all()
+                .iter()
+                .any(|t| t.matches(&f.derivation_path))
+        }) {
+            return Err(CommonError::NoMatchingDerivationTemplate);
+        }
+        let factor_source_ids = instances
+            .iter()
+            .map(|f| f.factor_source_id)
+            .collect::<IndexSet<_>>();
+        let per_factor_source = factor_source_ids
+            .into_iter()
+            .map(|factor_source_id| {
+                let existing = self
+                    .peek_all_instances_for_factor_source(factor_source_id)
+                    .unwrap_or_else(|| {
+                        CollectionsOfFactorInstances::empty(self.network_id, factor_source_id)
+                    });
+                let per_template = DerivationTemplate::all()
+                    .into_iter()
+                    .map(|template| {
+                        let merged = existing
+                            .instances_for_template(template)
+                            .into_iter()
+                            .chain(
+                                instances
+                                    .iter()
+                                    .filter(|f| f.factor_source_id == factor_source_id)
+                                    .filter(|f| template.matches(&f.derivation_path))
+                                    .cloned(),
+                            )
+                            .collect::<IndexSet<_>>()
+                            .into_iter()
+                            .sorted_by_key(|f| f.derivation_path.entity_index.index())
+                            .collect();
+                        (template, merged)
+                    })
+                    .collect();
+                CollectionsOfFactorInstances::with_instances(
+                    self.network_id,
+                    factor_source_id,
+                    per_template,
+                )
+                .map(|collections| (factor_source_id, collections))
+            })
+            .collect::<Result<Vec<_>>>()?;
+        self.per_factor_source
+            .write()
+            .unwrap()
+            .extend(per_factor_source);
+        let mut changes = self.changes.write().unwrap();
+        for instance in instances {
+            if !changes.consumed.shift_remove(&instance) {
+                changes.appended.insert(instance);
+            }
+        }
+        Ok(())
+    }
+
+    /// Records `instances` - derived from self, whether cached or used
+    /// directly - as reserved, so that merging self fails if any of them
+    /// was concurrently derived or consumed from another snapshot.
+    pub fn reserve(&self, instances: &IndexSet<HDFactorInstance>) {
+        self.changes.write().unwrap().reserve(
+            instances
+                .iter()
+                .filter(|f| f.derivation_path.network_id == self.network_id),
+        );
+    }
+
+    fn record_consumed(&self, instances: &IndexSet<HDFactorInstance>) {
+        let mut changes = self.changes.write().unwrap();
+        changes.reserve(instances);
+        for instance in instances {
+            if !changes.appended.shift_remove(instance) {
+                changes.consumed.insert(instance.clone());
+            }
+        }
+    }
+
+    /// Every instance of every factor source in the cache.
+    fn all_instances(&self) -> IndexSet<HDFactorInstance> {
+        self.per_factor_source
+            .read()
+            .unwrap()
+            .values()
+            .flat_map(|c| {
+                DerivationTemplate::all()
+                    .into_iter()
+                    .flat_map(|t| c.instances_for_template(t))
+            })
+            .collect()
+    }
+
+    /// Applies the changes made to self onto `current`, a newer version of
+    /// the cache self is a snapshot of. Fails if any index reserved by self
+    /// was reserved by any merge since the version of self, i.e. it was
+    /// derived or consumed concurrently, or if `current` does not remember
+    /// all those merges.
+    fn rebased_onto(&self, current: &Self) -> Result<Self> {
+        let changes = self.changes.read().unwrap().clone();
+        let since = current
+            .merge_history
+            .iter()
+            .filter(|(version, _)| *version > self.version)
+            .collect_vec();
+        if current.version.checked_sub(self.version) != Some(since.len() as u64)
+            || since.iter().any(|(_, reserved)| changes.overlaps(reserved))
+        {
+            return Err(CommonError::StaleCacheSnapshot);
+        }
+        let in_current = current.all_instances();
+        if !changes.consumed.iter().all(|f| in_current.contains(f)) {
+            return Err(CommonError::StaleCacheSnapshot);
         }
+        let rebased = Self::empty(self.network_id);
+        let factor_source_ids = current
+            .all_factor_sources()
+            .keys()
+            .copied()
+            .chain(changes.appended.iter().map(|f| f.factor_source_id))
+            .collect::<IndexSet<_>>();
+        for factor_source_id in factor_source_ids {
+            let per_template = DerivationTemplate::all()
+                .into_iter()
+                .map(|template| {
+                    let instances = in_current
+                        .iter()
+                        .chain(changes.appended.iter())
+                        .filter(|f| f.factor_source_id == factor_source_id)
+                        .filter(|f| template.matches(&f.derivation_path))
+                        .filter(|f| !changes.consumed.contains(*f))
+                        .cloned()
+                        .collect::<IndexSet<_>>()
+                        .into_iter()
+                        .sorted_by_key(|f| f.derivation_path.entity_index.index())
+                        .collect();
+                    (template, instances)
+                })
+                .collect();
+            rebased.append_for_factor(
+                factor_source_id,
+                ToCache(CollectionsOfFactorInstances::with_instances(
+                    self.network_id,
+                    factor_source_id,
+                    per_template,
+                )?),
+            )?;
+        }
+        Ok(rebased)
     }
     pub fn append_for_factor(
         &self,
         factor_source_id: FactorSourceID,
         instances: ToCache,
     ) -> Result<()> {
-        assert_eq!(self.network_id, instances.0.network);
-        assert_eq!(factor_source_id, instances.0.factor_source_id);
-        todo!()
+        if instances.0.network() != self.network_id {
+            return Err(CommonError::NetworkDiscrepancy {
+                expected: self.network_id,
+                found: instances.0.network(),
+            });
+        }
+        if instances.0.factor_source_id() != factor_source_id {
+            return Err(CommonError::FactorSourceDiscrepancy {
+                expected: factor_source_id,
+                found: instances.0.factor_source_id(),
+            });
+        }
+        let appended = DerivationTemplate::all()
+            .into_iter()
+            .flat_map(|t| instances.0.instances_for_template(t))
+            .collect::<IndexSet<_>>();
+        self.per_factor_source
+            .write()
+            .unwrap()
+            .entry(factor_source_id)
+            .or_insert_with(|| {
+                CollectionsOfFactorInstances::empty(self.network_id, factor_source_id)
+            })
+            .append_all(instances.0)?;
+        let mut changes = self.changes.write().unwrap();
+        changes.reserve(&appended);
+        changes.appended.extend(appended);
+        Ok(())
+    }
+
+    /// Appends `instances` to the set of `template` of `factor_source_id`
+    /// without validating, used by tests to corrupt the cache.
+    #[cfg(test)]
+    pub(crate) fn append_unvalidated(
+        &self,
+        factor_source_id: FactorSourceID,
+        template: DerivationTemplate,
+        instances: IndexSet<HDFactorInstance>,
+    ) -> Result<()> {
+        self.per_factor_source
+            .write()
+            .unwrap()
+            .entry(factor_source_id)
+            .or_insert_with(|| {
+                CollectionsOfFactorInstances::empty(self.network_id, factor_source_id)
+            })
+            .append_unvalidated(template, instances)
+    }
+}
+
+/// Zero or more instances consumed from the cache, for a single
+/// factor source and `DerivationTemplate`.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct FactorInstancesFromCache {
+    hidden_constructor: HiddenConstructor,
+    pub instances: IndexSet<HDFactorInstance>,
+    /// if the collection of instances is empty after consuming, if it is,
+    /// we SHOULD derive more!
+    pub was_last_used: bool,
+}
+impl FactorInstancesFromCache {
+    pub fn new(instances: IndexSet<HDFactorInstance>, was_last_used: bool) -> Self {
+        Self {
+            hidden_constructor: HiddenConstructor,
+            instances,
+            was_last_used,
+        }
     }
 }
 
@@ -51,7 +364,10 @@ impl FactorInstancesForSpecificNetworkCache {
         Self {
             hidden_constructor: HiddenConstructor,
             network_id: network,
+            version: 0,
             per_factor_source: RwLock::new(IndexMap::new()),
+            changes: RwLock::new(CacheChanges::default()),
+            merge_history: Vec::new(),
         }
     }
 
@@ -64,27 +380,82 @@ impl FactorInstancesForSpecificNetworkCache {
         let mut binding = self.per_factor_source.write().unwrap();
         let collections = binding.get_mut(&factor_source_id).unwrap_or(&mut default);
         if let Some(first) = collections.take_first_account_veci() {
+            self.record_consumed(&IndexSet::from_iter([first.instance()]));
             Some(FactorInstanceFromCache::new(
                 first.instance(),
-                collections.unsecurified_accounts.is_empty(),
+                collections.unsecurified_accounts().is_empty(),
             ))
         } else {
             None
         }
     }
 
-    /// Does NOT mutate self
-    pub fn peek_all_instances_for_factor_source(
+    /// Mutates self, consumes the next identity veci if any, else returns None
+    pub fn consume_identity_veci(
+        &self,
+        factor_source_id: FactorSourceID,
+    ) -> Option<FactorInstanceFromCache> {
+        let consumed = self.consume(factor_source_id, DerivationTemplate::IdentityVeci, 1);
+        consumed
+            .instances
+            .first()
+            .map(|first| FactorInstanceFromCache::new(first.clone(), consumed.was_last_used))
+    }
+
+    /// Mutates self, consumes (at most) the `quantity` first instances of
+    /// `template`, might be fewer than `quantity`, even zero.
+    pub fn consume(
+        &self,
+        factor_source_id: FactorSourceID,
+        template: DerivationTemplate,
+        quantity: usize,
+    ) -> FactorInstancesFromCache {
+        let mut binding = self.per_factor_source.write().unwrap();
+        let Some(collections) = binding.get_mut(&factor_source_id) else {
+            return FactorInstancesFromCache::new(IndexSet::new(), false);
+        };
+        let instances = collections.take_first(template, quantity);
+        self.record_consumed(&instances);
+        let was_last_used =
+            !instances.is_empty() && collections.instances_for_template(template).is_empty();
+        FactorInstancesFromCache::new(instances, was_last_used)
+    }
+
+    /// Mutates self, removes every instance of `factor_source_id`, returning
+    /// them, if any.
+    pub fn remove_factor_source(
         &self,
         factor_source_id: FactorSourceID,
     ) -> Option<CollectionsOfFactorInstances> {
-        todo!()
+        let removed = self
+            .per_factor_source
+            .write()
+            .unwrap()
+            .shift_remove(&factor_source_id)?;
+        self.record_consumed(
+            &DerivationTemplate::all()
+                .into_iter()
+                .flat_map(|t| removed.instances_for_template(t))
+                .collect(),
+        );
+        Some(removed)
     }
-}
 
-impl CollectionsOfFactorInstances {
-    pub fn take_first_account_veci(&mut self) -> Option<AccountVeci> {
-        todo!()
+    /// A snapshot of the instances of every factor source in the cache.
+    pub fn all_factor_sources(&self) -> IndexMap<FactorSourceID, CollectionsOfFactorInstances> {
+        self.per_factor_source.read().unwrap().clone()
+    }
+
+    /// Does NOT mutate self
+    pub fn peek_all_instances_for_factor_source(
+        &self,
+        factor_source_id: FactorSourceID,
+    ) -> Option<CollectionsOfFactorInstances> {
+        self.per_factor_source
+            .read()
+            .unwrap()
+            .get(&factor_source_id)
+            .cloned()
     }
 }
 
@@ -93,8 +464,44 @@ pub struct FactorInstancesForEachNetworkCache {
     #[allow(dead_code)]
     hidden_constructor: HiddenConstructor,
     pub networks: HashMap<NetworkID, FactorInstancesForSpecificNetworkCache>,
+    /// Instances taken out of the cache, but not yet committed nor released.
+    pub leases: IndexMap<LeaseID, FactorInstancesLease>,
+    /// The ID of the next lease, never reused.
+    pub next_lease_id: u64,
+    /// Instances provided to be used in Profile, journaled until reconciled
+    /// with the saved Profile.
+    pub pending_consumptions: Vec<PendingConsumption>,
 }
 impl FactorInstancesForEachNetworkCache {
+    pub fn cloned_snapshot(&self) -> Self {
+        Self {
+            hidden_constructor: HiddenConstructor,
+            networks: self
+                .networks
+                .iter()
+                .map(|(k, v)| (*k, v.cloned_snapshot()))
+                .collect(),
+            leases: self.leases.clone(),
+            next_lease_id: self.next_lease_id,
+            pending_consumptions: self.pending_consumptions.clone(),
+        }
+    }
+    /// The instances on `network_id` taken out of the cache but maybe not
+    /// yet used in Profile, i.e. leased or pending in the journal.
+    pub fn outstanding_instances(&self, network_id: NetworkID) -> IndexSet<HDFactorInstance> {
+        self.leases
+            .values()
+            .filter(|l| l.network_id == network_id)
+            .flat_map(|l| l.instances.iter())
+            .chain(
+                self.pending_consumptions
+                    .iter()
+                    .filter(|p| p.network_id == network_id)
+                    .flat_map(|p| p.instances.iter()),
+            )
+            .cloned()
+            .collect()
+    }
     pub fn clone_for_network_or_empty(
         &self,
         network_id: NetworkID,
@@ -108,7 +515,392 @@ impl FactorInstancesForEachNetworkCache {
     ) -> Option<FactorInstancesForSpecificNetworkCache> {
         self.networks.get(&network_id).map(|x| x.cloned_snapshot())
     }
-    pub fn merge(&self, on_network: FactorInstancesForSpecificNetworkCache) -> Result<()> {
-        todo!()
+    /// Replaces the cache on the network of `on_network` with it, and bumps
+    /// the version of that network.
+    ///
+    /// If `on_network` is a snapshot of an older version, i.e. the cache was
+    /// merged since the snapshot was taken, the changes made to the snapshot
+    /// are rebased onto the current cache instead. Fails with
+    /// `StaleCacheSnapshot` if the snapshot reserved - derived or consumed -
+    /// any index reserved by a merge since, since the instance at it might
+    /// have been used already.
+    pub fn merge(&mut self, on_network: FactorInstancesForSpecificNetworkCache) -> Result<()> {
+        let network_id = on_network.network_id;
+        let current = self.clone_for_network_or_empty(network_id);
+        let reserved = on_network.changes.read().unwrap().reserved.clone();
+        let merged = if on_network.version() == current.version() {
+            on_network
+        } else {
+            on_network.rebased_onto(&current)?
+        };
+        let version = current.version() + 1;
+        let mut merge_history = current.merge_history;
+        merge_history.push((version, reserved));
+        if merge_history.len() > MERGE_HISTORY_LENGTH {
+            merge_history.remove(0);
+        }
+        self.networks.insert(
+            network_id,
+            FactorInstancesForSpecificNetworkCache {
+                merge_history,
+                ..merged.with_version(version)
+            },
+        );
+        Ok(())
+    }
+
+    /// Returns `instances` - provided but not used after all - to the cache
+    /// on `network_id`, and forgets them in every lease and pending
+    /// consumption, so that they are not put back again once the lease has
+    /// expired or the journal is reconciled.
+    ///
+    /// Fails without changing anything if any of them is used in `profile`,
+    /// is not on `network_id` or is not valid for any template.
+    pub fn return_instances(
+        &mut self,
+        network_id: NetworkID,
+        instances: IndexSet<HDFactorInstance>,
+        profile: &Profile,
+    ) -> Result<()> {
+        let on_network = self.clone_for_network_or_empty(network_id);
+        on_network.return_instances(instances.clone(), profile)?;
+        self.merge(on_network)?;
+        for lease in self.leases.values_mut() {
+            lease.instances.retain(|f| !instances.contains(f));
+        }
+        self.leases.retain(|_, l| !l.instances.is_empty());
+        for pending in self.pending_consumptions.iter_mut() {
+            pending.instances.retain(|f| !instances.contains(f));
+        }
+        self.pending_consumptions
+            .retain(|p| !p.instances.is_empty());
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::SystemTime;
+
+    use super::*;
+
+    type Sut = FactorInstancesForEachNetworkCache;
+
+    fn cache_with_account_vecis(quantity: u32) -> Sut {
+        let network_id = NetworkID::Mainnet;
+        let factor_source = HDFactorSource::sample();
+        let id = factor_source.factor_source_id;
+        let on_network = FactorInstancesForSpecificNetworkCache::empty(network_id);
+        let per_template = [
+            DerivationTemplate::AccountVeci,
+            DerivationTemplate::AccountMfa,
+        ]
+        .into_iter()
+        .map(|t| {
+            let paths = (0..quantity)
+                .map(|i| t.derivation_path(network_id, i).unwrap())
+                .collect();
+            (t, factor_source.derive(&paths).unwrap())
+        })
+        .collect();
+        on_network
+            .append_for_factor(
+                id,
+                ToCache(
+                    CollectionsOfFactorInstances::with_instances(network_id, id, per_template)
+                        .unwrap(),
+                ),
+            )
+            .unwrap();
+        let mut sut = Sut::default();
+        sut.merge(on_network).unwrap();
+        sut
+    }
+
+    #[test]
+    fn consume_identity_veci_until_empty() {
+        let network_id = NetworkID::Mainnet;
+        let factor_source = HDFactorSource::sample();
+        let id = factor_source.factor_source_id;
+        let paths = (0..2)
+            .map(|i| {
+                DerivationTemplate::IdentityVeci
+                    .derivation_path(network_id, i)
+                    .unwrap()
+            })
+            .collect();
+        let sut = FactorInstancesForSpecificNetworkCache::empty(network_id);
+        sut.append_for_factor(
+            id,
+            ToCache(
+                CollectionsOfFactorInstances::with_instances(
+                    network_id,
+                    id,
+                    IndexMap::from_iter([(
+                        DerivationTemplate::IdentityVeci,
+                        factor_source.derive(&paths).unwrap(),
+                    )]),
+                )
+                .unwrap(),
+            ),
+        )
+        .unwrap();
+
+        let consumed = [(); 3].map(|_| {
+            sut.consume_identity_veci(id)
+                .map(|f| (f.instance.derivation_path.entity_index, f.was_last_used))
+        });
+
+        assert_eq!(
+            consumed,
+            [
+                Some((CAP26EntityIndex::Unsecurified(0), false)),
+                Some((CAP26EntityIndex::Unsecurified(1), true)),
+                None
+            ]
+        );
+        assert_eq!(sut.consume_account_veci(id), None);
+    }
+
+    #[test]
+    fn merge_bumps_version() {
+        let mut sut = cache_with_account_vecis(2);
+        let snapshot = sut.clone_for_network(NetworkID::Mainnet).unwrap();
+        assert_eq!(snapshot.version(), 1);
+        sut.merge(snapshot).unwrap();
+        assert_eq!(
+            sut.clone_for_network(NetworkID::Mainnet).unwrap().version(),
+            2
+        );
+    }
+
+    #[test]
+    fn merge_of_stale_snapshot_consuming_same_instance_is_err() {
+        let mut sut = cache_with_account_vecis(2);
+        let id = FactorSourceID::sample();
+        let first = sut.clone_for_network(NetworkID::Mainnet).unwrap();
+        let second = sut.clone_for_network(NetworkID::Mainnet).unwrap();
+        first.consume(id, DerivationTemplate::AccountVeci, 1);
+        second.consume(id, DerivationTemplate::AccountVeci, 1);
+
+        sut.merge(first).unwrap();
+
+        assert_eq!(sut.merge(second), Err(CommonError::StaleCacheSnapshot));
+        assert_eq!(
+            sut.clone_for_network(NetworkID::Mainnet)
+                .unwrap()
+                .peek_all_instances_for_factor_source(id)
+                .unwrap()
+                .unsecurified_accounts()
+                .len(),
+            1
+        );
+    }
+
+    #[test]
+    fn merge_of_stale_snapshot_without_conflict_is_rebased() {
+        let mut sut = cache_with_account_vecis(2);
+        let id = FactorSourceID::sample();
+        let first = sut.clone_for_network(NetworkID::Mainnet).unwrap();
+        let second = sut.clone_for_network(NetworkID::Mainnet).unwrap();
+        first.consume(id, DerivationTemplate::AccountVeci, 1);
+        second.consume(id, DerivationTemplate::AccountMfa, 1);
+
+        sut.merge(first).unwrap();
+        sut.merge(second).unwrap();
+
+        let merged = sut.clone_for_network(NetworkID::Mainnet).unwrap();
+        assert_eq!(merged.version(), 3);
+        let collections = merged.peek_all_instances_for_factor_source(id).unwrap();
+        assert_eq!(
+            collections
+                .unsecurified_accounts()
+                .iter()
+                .map(|f| f.derivation_entity_index())
+                .collect_vec(),
+            vec![CAP26EntityIndex::Unsecurified(1)]
+        );
+        assert_eq!(
+            collections
+                .securified_accounts()
+                .iter()
+                .map(|f| f.derivation_entity_index())
+                .collect_vec(),
+            vec![CAP26EntityIndex::Securified(1)]
+        );
+    }
+
+    #[test]
+    fn put_back_restores_index_order() {
+        let sut = cache_with_account_vecis(3);
+        let id = FactorSourceID::sample();
+        let on_network = sut.clone_for_network(NetworkID::Mainnet).unwrap();
+        let consumed = on_network
+            .consume(id, DerivationTemplate::AccountVeci, 2)
+            .instances;
+
+        on_network
+            .put_back(consumed.into_iter().rev().collect())
+            .unwrap();
+
+        assert_eq!(
+            on_network
+                .peek_all_instances_for_factor_source(id)
+                .unwrap()
+                .unsecurified_accounts()
+                .iter()
+                .map(|f| f.derivation_entity_index())
+                .collect_vec(),
+            (0..3).map(CAP26EntityIndex::Unsecurified).collect_vec()
+        );
+    }
+
+    #[test]
+    fn return_instances_used_in_profile_is_err() {
+        let sut = cache_with_account_vecis(3);
+        let id = FactorSourceID::sample();
+        let on_network = sut.clone_for_network(NetworkID::Mainnet).unwrap();
+        let consumed = on_network
+            .consume(id, DerivationTemplate::AccountVeci, 2)
+            .instances;
+        let used = consumed.first().unwrap().clone();
+        let profile = Profile {
+            factor_sources: IndexSet::new(),
+            networks: IndexMap::from_iter([(
+                NetworkID::Mainnet,
+                ProfileOnNetwork {
+                    network_id: NetworkID::Mainnet,
+                    accounts: IndexSet::from_iter([Account::new(
+                        EntitySecurityState::Unsecurified(used),
+                    )]),
+                    personas: IndexSet::new(),
+                },
+            )]),
+        };
+
+        assert_eq!(
+            on_network.return_instances(consumed, &profile),
+            Err(CommonError::InstanceUsedInProfile)
+        );
+        assert_eq!(
+            on_network
+                .peek_all_instances_for_factor_source(id)
+                .unwrap()
+                .unsecurified_accounts()
+                .len(),
+            1
+        );
+    }
+
+    #[test]
+    fn return_instances_of_other_network_is_err() {
+        let sut = cache_with_account_vecis(1);
+        let on_network = sut.clone_for_network(NetworkID::Mainnet).unwrap();
+        let on_testnet = HDFactorSource::sample()
+            .derive(&IndexSet::from_iter([DerivationTemplate::AccountVeci
+                .derivation_path(NetworkID::Testnet, 0)
+                .unwrap()]))
+            .unwrap();
+
+        assert_eq!(
+            on_network.return_instances(on_testnet, &Profile::default()),
+            Err(CommonError::CollectionNetworkDiscrepancy {
+                factor_source_id: FactorSourceID::sample(),
+                derivation_path: DerivationTemplate::AccountVeci
+                    .derivation_path(NetworkID::Testnet, 0)
+                    .unwrap(),
+            })
+        );
+    }
+
+    #[test]
+    fn returned_instances_are_forgotten_in_leases_and_journal() {
+        let mut sut = cache_with_account_vecis(3);
+        let id = FactorSourceID::sample();
+        let on_network = sut.clone_for_network(NetworkID::Mainnet).unwrap();
+        let leased = on_network
+            .consume(id, DerivationTemplate::AccountVeci, 2)
+            .instances;
+        let pending = on_network
+            .consume(id, DerivationTemplate::AccountVeci, 1)
+            .instances;
+        sut.merge(on_network).unwrap();
+        sut.lease(NetworkID::Mainnet, leased.clone(), SystemTime::now());
+        sut.journal_consumption(NetworkID::Mainnet, pending.clone());
+
+        let returned = leased
+            .iter()
+            .take(1)
+            .chain(pending.iter())
+            .cloned()
+            .collect::<IndexSet<_>>();
+        sut.return_instances(NetworkID::Mainnet, returned, &Profile::default())
+            .unwrap();
+
+        assert_eq!(
+            sut.leases
+                .values()
+                .flat_map(|l| l.instances.clone())
+                .collect::<IndexSet<_>>(),
+            leased.into_iter().skip(1).collect::<IndexSet<_>>()
+        );
+        assert!(sut.pending_consumptions.is_empty());
+        assert_eq!(
+            sut.clone_for_network(NetworkID::Mainnet)
+                .unwrap()
+                .peek_all_instances_for_factor_source(id)
+                .unwrap()
+                .unsecurified_accounts()
+                .iter()
+                .map(|f| f.derivation_entity_index())
+                .collect_vec(),
+            [0, 2].map(CAP26EntityIndex::Unsecurified).to_vec()
+        );
+    }
+
+    #[test]
+    fn append_for_other_factor_source_is_err() {
+        let on_network = FactorInstancesForSpecificNetworkCache::empty(NetworkID::Mainnet);
+        assert_eq!(
+            on_network.append_for_factor(
+                FactorSourceID::sample(),
+                ToCache(CollectionsOfFactorInstances::empty(
+                    NetworkID::Mainnet,
+                    FactorSourceID::sample_other()
+                )),
+            ),
+            Err(CommonError::FactorSourceDiscrepancy {
+                expected: FactorSourceID::sample(),
+                found: FactorSourceID::sample_other(),
+            })
+        );
+    }
+
+    #[test]
+    fn failed_append_records_no_changes() {
+        let network_id = NetworkID::Mainnet;
+        let factor_source = HDFactorSource::sample();
+        let id = factor_source.factor_source_id;
+        let to_cache = |index| {
+            let instances = factor_source
+                .derive(&IndexSet::from_iter([DerivationTemplate::AccountVeci
+                    .derivation_path(network_id, index)
+                    .unwrap()]))
+                .unwrap();
+            ToCache(
+                CollectionsOfFactorInstances::with_instances(
+                    network_id,
+                    id,
+                    IndexMap::from_iter([(DerivationTemplate::AccountVeci, instances)]),
+                )
+                .unwrap(),
+            )
+        };
+        let on_network = FactorInstancesForSpecificNetworkCache::empty(network_id);
+        on_network.append_for_factor(id, to_cache(1)).unwrap();
+        let changes = on_network.changes.read().unwrap().clone();
+
+        assert!(on_network.append_for_factor(id, to_cache(0)).is_err());
+        assert_eq!(*on_network.changes.read().unwrap(), changes);
     }
 }
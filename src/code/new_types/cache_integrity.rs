@@ -0,0 +1,406 @@
+use crate::prelude::*;
+
+/// A problem found in the cache by `check_integrity`.
+#[derive(Clone, Debug, PartialEq, Eq, Hash)]
+pub enum CacheIntegrityIssue {
+    /// The instance is in the cache more than once, or is both in the cache
+    /// and leased or pending.
+    Duplicate { instance: HDFactorInstance },
+
+    /// The instance is in the set of `template`, which does not match its
+    /// derivation path.
+    WrongTemplate {
+        instance: HDFactorInstance,
+        template: DerivationTemplate,
+    },
+
+    /// The instance is in the cache of `network_id`, but its derivation path
+    /// is for another network.
+    WrongNetwork {
+        instance: HDFactorInstance,
+        network_id: NetworkID,
+    },
+
+    /// The instance is in the collections of `factor_source_id`, but was
+    /// derived by another factor source.
+    WrongFactorSource {
+        instance: HDFactorInstance,
+        factor_source_id: FactorSourceID,
+    },
+
+    /// The instance is used by an Account or Persona in Profile.
+    UsedInProfile { instance: HDFactorInstance },
+
+    /// No instance at `derivation_path` is cached, leased, pending or used in
+    /// Profile, even though instances at both lower and higher indices are
+    /// cached.
+    MissingIndex {
+        factor_source_id: FactorSourceID,
+        derivation_path: DerivationPath,
+    },
+}
+
+/// Every problem found in the cache, empty if the cache is intact.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct CacheIntegrityReport {
+    pub issues: IndexSet<CacheIntegrityIssue>,
+}
+impl CacheIntegrityReport {
+    pub fn is_intact(&self) -> bool {
+        self.issues.is_empty()
+    }
+
+    /// The paths of the `MissingIndex` issues, per factor source.
+    pub fn missing_paths(&self) -> IndexMap<FactorSourceID, IndexSet<DerivationPath>> {
+        let mut missing = IndexMap::<FactorSourceID, IndexSet<DerivationPath>>::new();
+        for issue in self.issues.iter() {
+            if let CacheIntegrityIssue::MissingIndex {
+                factor_source_id,
+                derivation_path,
+            } = issue
+            {
+                missing
+                    .entry(*factor_source_id)
+                    .or_default()
+                    .insert(*derivation_path);
+            }
+        }
+        missing
+    }
+}
+
+/// Identifies an instance, two instances at the same path derived by the same
+/// factor source are the same key.
+type InstanceID = (FactorSourceID, DerivationPath);
+
+fn id_of(instance: &HDFactorInstance) -> InstanceID {
+    (instance.factor_source_id, instance.derivation_path)
+}
+
+impl FactorInstancesForEachNetworkCache {
+    /// Finds every `CacheIntegrityIssue` of the cache against `profile`.
+    pub fn check_integrity(&self, profile: &Profile) -> CacheIntegrityReport {
+        self.check(profile).0
+    }
+
+    /// Drops every bad instance found by `check_integrity` - keeping the
+    /// first of duplicates in the cache. Returns the report of the issues
+    /// found, before repairing.
+    ///
+    /// Missing indices are reported but never filled: an index might be
+    /// missing because it is used on ledger by an entity no longer in
+    /// Profile - e.g. skipped by Account Recovery Scan or securified - and
+    /// filling it would hand out the same key twice.
+    pub fn repair(&mut self, profile: &Profile) -> Result<CacheIntegrityReport> {
+        let (report, kept) = self.check(profile);
+        let network_ids = self.networks.keys().copied().collect::<IndexSet<_>>();
+        for network_id in network_ids {
+            let current = self.clone_for_network_or_empty(network_id);
+            let repaired = FactorInstancesForSpecificNetworkCache::empty(network_id);
+            for factor_source_id in current.all_factor_sources().keys().copied() {
+                let per_template = DerivationTemplate::all()
+                    .into_iter()
+                    .map(|template| {
+                        let instances = kept
+                            .iter()
+                            .filter(|f| {
+                                f.factor_source_id == factor_source_id
+                                    && f.derivation_path.network_id == network_id
+                                    && template.matches(&f.derivation_path)
+                            })
+                            .sorted_by_key(|f| f.derivation_path.entity_index.index())
+                            .cloned()
+                            .collect();
+                        (template, instances)
+                    })
+                    .collect();
+                repaired.append_for_factor(
+                    factor_source_id,
+                    ToCache(CollectionsOfFactorInstances::with_instances(
+                        network_id,
+                        factor_source_id,
+                        per_template,
+                    )?),
+                )?;
+            }
+            self.merge(repaired.with_version(current.version()))?;
+        }
+        Ok(report)
+    }
+
+    /// The report and the instances in the cache which are fine.
+    fn check(&self, profile: &Profile) -> (CacheIntegrityReport, IndexSet<HDFactorInstance>) {
+        let in_profile = profile
+            .all_factor_instances()
+            .iter()
+            .map(id_of)
+            .collect::<IndexSet<_>>();
+        let outstanding = self
+            .leases
+            .values()
+            .flat_map(|l| l.instances.iter())
+            .chain(
+                self.pending_consumptions
+                    .iter()
+                    .flat_map(|p| p.instances.iter()),
+            )
+            .map(id_of)
+            .collect::<IndexSet<_>>();
+
+        let mut issues = IndexSet::new();
+        let mut seen = IndexSet::<InstanceID>::new();
+        let mut kept = IndexSet::new();
+        for (network_id, on_network) in self
+            .networks
+            .iter()
+            .sorted_by_key(|(n, _)| n.discriminant())
+        {
+            for (factor_source_id, collections) in on_network.all_factor_sources() {
+                for template in DerivationTemplate::all() {
+                    for instance in collections.instances_for_template(template) {
+                        let id = id_of(&instance);
+                        let issue = if !seen.insert(id) || outstanding.contains(&id) {
+                            Some(CacheIntegrityIssue::Duplicate { instance })
+                        } else if instance.derivation_path.network_id != *network_id {
+                            Some(CacheIntegrityIssue::WrongNetwork {
+                                instance,
+                                network_id: *network_id,
+                            })
+                        } else if instance.factor_source_id != factor_source_id {
+                            Some(CacheIntegrityIssue::WrongFactorSource {
+                                instance,
+                                factor_source_id,
+                            })
+                        } else if !template.matches(&instance.derivation_path) {
+                            Some(CacheIntegrityIssue::WrongTemplate { instance, template })
+                        } else if in_profile.contains(&id) {
+                            Some(CacheIntegrityIssue::UsedInProfile { instance })
+                        } else {
+                            kept.insert(instance);
+                            None
+                        };
+                        issues.extend(issue);
+                    }
+                }
+            }
+        }
+
+        let accounted_for = kept
+            .iter()
+            .map(id_of)
+            .chain(outstanding)
+            .chain(in_profile)
+            .collect::<IndexSet<_>>();
+        let mut sequences = IndexMap::<_, Vec<&HDFactorInstance>>::new();
+        for instance in kept.iter() {
+            let path = instance.derivation_path;
+            sequences
+                .entry((
+                    instance.factor_source_id,
+                    path.network_id,
+                    path.entity_kind,
+                    path.key_kind,
+                    path.key_space(),
+                ))
+                .or_default()
+                .push(instance);
+        }
+        for ((factor_source_id, ..), instances) in sequences {
+            let Some((first, last)) = instances
+                .iter()
+                .map(|f| f.derivation_path)
+                .minmax_by_key(|p| p.entity_index.index())
+                .into_option()
+            else {
+                continue;
+            };
+            for index in first.entity_index.index()..last.entity_index.index() {
+                let derivation_path = DerivationPath {
+                    entity_index: CAP26EntityIndex::new(first.key_space(), index),
+                    ..first
+                };
+                if !accounted_for.contains(&(factor_source_id, derivation_path)) {
+                    issues.insert(CacheIntegrityIssue::MissingIndex {
+                        factor_source_id,
+                        derivation_path,
+                    });
+                }
+            }
+        }
+
+        (CacheIntegrityReport { issues }, kept)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::{Duration, SystemTime};
+
+    use super::*;
+
+    type Sut = FactorInstancesForEachNetworkCache;
+
+    fn account_veci(
+        factor_source: &HDFactorSource,
+        network_id: NetworkID,
+        index: u32,
+    ) -> HDFactorInstance {
+        factor_source
+            .derive(&IndexSet::from_iter([DerivationTemplate::AccountVeci
+                .derivation_path(network_id, index)
+                .unwrap()]))
+            .unwrap()
+            .into_iter()
+            .next()
+            .unwrap()
+    }
+
+    /// Account vecis at indices 0, 1, 3 and 4 of which 0 is used in Profile
+    /// and 4 is leased, and two foreign instances slipped in.
+    fn corrupted() -> (Sut, Profile) {
+        let bdfs = HDFactorSource::sample();
+        let id = bdfs.factor_source_id;
+        let network_id = NetworkID::Mainnet;
+        let collections = CollectionsOfFactorInstances::with_instances(
+            network_id,
+            id,
+            IndexMap::from_iter([(
+                DerivationTemplate::AccountVeci,
+                [0, 1, 3, 4]
+                    .into_iter()
+                    .map(|i| account_veci(&bdfs, network_id, i))
+                    .collect(),
+            )]),
+        )
+        .unwrap();
+        let on_network = FactorInstancesForSpecificNetworkCache::empty(network_id);
+        on_network
+            .append_for_factor(id, ToCache(collections))
+            .unwrap();
+        let mut sut = Sut::default();
+        sut.merge(on_network).unwrap();
+        sut.networks[&network_id]
+            .append_unvalidated(
+                id,
+                DerivationTemplate::AccountVeci,
+                IndexSet::from_iter([
+                    account_veci(&bdfs, NetworkID::Testnet, 5),
+                    account_veci(&HDFactorSource::sample_other(), network_id, 6),
+                ]),
+            )
+            .unwrap();
+        sut.lease(
+            network_id,
+            IndexSet::from_iter([account_veci(&bdfs, network_id, 4)]),
+            SystemTime::now() + Duration::from_secs(60),
+        );
+        let profile = Profile {
+            factor_sources: IndexSet::new(),
+            networks: IndexMap::from_iter([(
+                network_id,
+                ProfileOnNetwork {
+                    network_id,
+                    accounts: IndexSet::from_iter([Account::new(
+                        EntitySecurityState::Unsecurified(account_veci(&bdfs, network_id, 0)),
+                    )]),
+                    personas: IndexSet::new(),
+                },
+            )]),
+        };
+        (sut, profile)
+    }
+
+    #[test]
+    fn filled_cache_is_intact() {
+        let on_network = FactorInstancesForSpecificNetworkCache::empty(NetworkID::Mainnet);
+        let bdfs = HDFactorSource::sample();
+        let per_template = DerivationTemplate::all()
+            .into_iter()
+            .map(|t| {
+                let paths = (0..3)
+                    .map(|i| t.derivation_path(NetworkID::Mainnet, i).unwrap())
+                    .collect();
+                (t, bdfs.derive(&paths).unwrap())
+            })
+            .collect();
+        on_network
+            .append_for_factor(
+                bdfs.factor_source_id,
+                ToCache(
+                    CollectionsOfFactorInstances::with_instances(
+                        NetworkID::Mainnet,
+                        bdfs.factor_source_id,
+                        per_template,
+                    )
+                    .unwrap(),
+                ),
+            )
+            .unwrap();
+        let mut sut = Sut::default();
+        sut.merge(on_network).unwrap();
+
+        assert!(sut.check_integrity(&Profile::default()).is_intact());
+    }
+
+    #[test]
+    fn check_integrity_reports_every_issue() {
+        let (sut, profile) = corrupted();
+        let bdfs = HDFactorSource::sample();
+        let mainnet = NetworkID::Mainnet;
+
+        assert_eq!(
+            sut.check_integrity(&profile).issues,
+            IndexSet::<CacheIntegrityIssue>::from_iter([
+                CacheIntegrityIssue::UsedInProfile {
+                    instance: account_veci(&bdfs, mainnet, 0)
+                },
+                CacheIntegrityIssue::Duplicate {
+                    instance: account_veci(&bdfs, mainnet, 4)
+                },
+                CacheIntegrityIssue::WrongNetwork {
+                    instance: account_veci(&bdfs, NetworkID::Testnet, 5),
+                    network_id: mainnet
+                },
+                CacheIntegrityIssue::WrongFactorSource {
+                    instance: account_veci(&HDFactorSource::sample_other(), mainnet, 6),
+                    factor_source_id: bdfs.factor_source_id
+                },
+                CacheIntegrityIssue::MissingIndex {
+                    factor_source_id: bdfs.factor_source_id,
+                    derivation_path: DerivationTemplate::AccountVeci
+                        .derivation_path(mainnet, 2)
+                        .unwrap()
+                },
+            ])
+        );
+    }
+
+    #[actix::test]
+    async fn repair_drops_bad_instances_and_keeps_gaps() {
+        let (sut, profile) = corrupted();
+        let storage = InMemoryFactorInstancesCacheStorage::new(sut);
+
+        let report = FactorInstancesProvider::repair_cache(&storage, profile.clone())
+            .await
+            .unwrap();
+
+        assert_eq!(report.issues.len(), 5);
+        let repaired = storage.load().await.unwrap();
+        assert_eq!(
+            repaired.check_integrity(&profile).missing_paths(),
+            report.missing_paths()
+        );
+        assert_eq!(
+            repaired
+                .clone_for_network(NetworkID::Mainnet)
+                .unwrap()
+                .peek_all_instances_for_factor_source(FactorSourceID::sample())
+                .unwrap()
+                .unsecurified_accounts()
+                .iter()
+                .map(|f| f.derivation_entity_index())
+                .collect_vec(),
+            [1, 3].map(CAP26EntityIndex::Unsecurified).to_vec()
+        );
+    }
+}
@@ -0,0 +1,742 @@
+use std::time::{Duration, SystemTime};
+
+use serde::{de::DeserializeOwned, Deserialize, Deserializer, Serialize, Serializer};
+
+use crate::prelude::*;
+
+/// The version of the persisted format of `FactorInstancesForEachNetworkCache`,
+/// MUST be bumped on any change to the format, migrating the previous version
+/// in `Encoded::snapshot`.
+pub const CACHE_SERIALIZATION_VERSION: u32 = 4;
+
+impl FactorInstancesForEachNetworkCache {
+    /// A stable, versioned JSON representation of the cache.
+    pub fn to_json(&self) -> String {
+        serde_json::to_string_pretty(&CacheSnapshot::from(self))
+            .expect("Snapshot is always serializable")
+    }
+
+    /// Fails if `json` is not of a supported version or contains an invalid
+    /// instance, e.g. one not matching the set it is in.
+    pub fn from_json(json: &str) -> Result<Self> {
+        let versioned = serde_json::from_str::<Versioned>(json)
+            .map_err(|_| CommonError::InvalidCacheEncoding)?;
+        Encoded::Json(json).snapshot(versioned.version)?.try_into()
+    }
+
+    /// A compact, versioned binary representation of the cache, the version
+    /// is always the first four bytes, little endian.
+    pub fn to_bytes(&self) -> Vec<u8> {
+        bincode::serialize(&CacheSnapshot::from(self)).expect("Snapshot is always serializable")
+    }
+
+    /// Fails if `bytes` is not of a supported version or contains an invalid
+    /// instance, e.g. one not matching the set it is in.
+    pub fn from_bytes(bytes: &[u8]) -> Result<Self> {
+        let version =
+            bincode::deserialize::<u32>(bytes).map_err(|_| CommonError::InvalidCacheEncoding)?;
+        Encoded::Bincode(bytes).snapshot(version)?.try_into()
+    }
+}
+
+/// A persisted cache, in either of its encodings.
+enum Encoded<'a> {
+    Json(&'a str),
+    Bincode(&'a [u8]),
+}
+impl Encoded<'_> {
+    fn decode<T: DeserializeOwned>(&self) -> Result<T> {
+        match self {
+            Self::Json(json) => {
+                serde_json::from_str(json).map_err(|_| CommonError::InvalidCacheEncoding)
+            }
+            Self::Bincode(bytes) => {
+                bincode::deserialize(bytes).map_err(|_| CommonError::InvalidCacheEncoding)
+            }
+        }
+    }
+
+    /// Decodes the snapshot as being of `version`, migrating the previous
+    /// versions to the current one.
+    fn snapshot(&self, version: u32) -> Result<CacheSnapshot> {
+        match version {
+            CACHE_SERIALIZATION_VERSION => self.decode(),
+            3 => self.decode::<CacheSnapshotV3>().map(CacheSnapshot::from),
+            2 => self.decode::<CacheSnapshotV2>().map(CacheSnapshot::from),
+            1 => self.decode::<CacheSnapshotV1>().map(CacheSnapshot::from),
+            found => Err(CommonError::UnsupportedCacheVersion { found }),
+        }
+    }
+}
+
+#[derive(Deserialize)]
+struct Versioned {
+    version: u32,
+}
+
+#[derive(Serialize, Deserialize)]
+struct CacheSnapshot {
+    /// MUST be the first field, read before the rest.
+    version: u32,
+    networks: Vec<NetworkSnapshot>,
+    next_lease_id: u64,
+    leases: Vec<LeaseSnapshot>,
+    pending_consumptions: Vec<PendingConsumptionSnapshot>,
+}
+
+/// Version 1, before the networks were stamped with their version.
+#[derive(Deserialize)]
+struct CacheSnapshotV1 {
+    #[serde(rename = "version")]
+    _version: u32,
+    networks: Vec<NetworkSnapshotV1>,
+}
+#[derive(Deserialize)]
+struct NetworkSnapshotV1 {
+    network_id: u32,
+    factor_sources: Vec<FactorSourceSnapshot>,
+}
+impl From<CacheSnapshotV1> for CacheSnapshot {
+    fn from(value: CacheSnapshotV1) -> Self {
+        CacheSnapshotV2 {
+            _version: 2,
+            networks: value
+                .networks
+                .into_iter()
+                .map(|n| NetworkSnapshot {
+                    network_id: n.network_id,
+                    stamp: 0,
+                    factor_sources: n.factor_sources,
+                })
+                .collect(),
+        }
+        .into()
+    }
+}
+
+/// Version 2, before leases and the journal.
+#[derive(Deserialize)]
+struct CacheSnapshotV2 {
+    #[serde(rename = "version")]
+    _version: u32,
+    networks: Vec<NetworkSnapshot>,
+}
+impl From<CacheSnapshotV2> for CacheSnapshot {
+    fn from(value: CacheSnapshotV2) -> Self {
+        Self {
+            version: CACHE_SERIALIZATION_VERSION,
+            networks: value.networks,
+            next_lease_id: 0,
+            leases: Vec::new(),
+            pending_consumptions: Vec::new(),
+        }
+    }
+}
+
+/// Version 3, before the journal.
+#[derive(Deserialize)]
+struct CacheSnapshotV3 {
+    #[serde(rename = "version")]
+    _version: u32,
+    networks: Vec<NetworkSnapshot>,
+    next_lease_id: u64,
+    leases: Vec<LeaseSnapshot>,
+}
+impl From<CacheSnapshotV3> for CacheSnapshot {
+    fn from(value: CacheSnapshotV3) -> Self {
+        Self {
+            version: CACHE_SERIALIZATION_VERSION,
+            networks: value.networks,
+            next_lease_id: value.next_lease_id,
+            leases: value.leases,
+            pending_consumptions: Vec::new(),
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize)]
+struct NetworkSnapshot {
+    network_id: u32,
+    /// The version of the cache on this network, not of the format.
+    stamp: u64,
+    factor_sources: Vec<FactorSourceSnapshot>,
+}
+
+#[derive(Serialize, Deserialize)]
+struct FactorSourceSnapshot {
+    kind: FactorSourceKindSnapshot,
+    body: Bytes,
+    unsecurified_accounts: Vec<InstanceSnapshot>,
+    unsecurified_identities: Vec<InstanceSnapshot>,
+    securified_accounts: Vec<InstanceSnapshot>,
+    securified_identities: Vec<InstanceSnapshot>,
+    securified_accounts_rola: Vec<InstanceSnapshot>,
+}
+
+#[derive(Serialize, Deserialize)]
+struct LeaseSnapshot {
+    id: u64,
+    network_id: u32,
+    /// Seconds since UNIX epoch.
+    expires_at: u64,
+    instances: Vec<FactorInstanceSnapshot>,
+}
+
+#[derive(Serialize, Deserialize)]
+struct PendingConsumptionSnapshot {
+    network_id: u32,
+    instances: Vec<FactorInstanceSnapshot>,
+}
+
+/// An instance of any factor source, unlike `InstanceSnapshot`.
+#[derive(Serialize, Deserialize)]
+struct FactorInstanceSnapshot {
+    kind: FactorSourceKindSnapshot,
+    body: Bytes,
+    derivation_path: String,
+    public_key: Bytes,
+}
+
+#[derive(Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+enum FactorSourceKindSnapshot {
+    Ledger,
+    Arculus,
+    OffDeviceMnemonic,
+    Device,
+}
+
+#[derive(Serialize, Deserialize)]
+struct InstanceSnapshot {
+    derivation_path: String,
+    public_key: Bytes,
+}
+
+/// Hex in human readable formats, e.g. JSON, else raw bytes.
+struct Bytes(Vec<u8>);
+impl Serialize for Bytes {
+    fn serialize<S: Serializer>(&self, serializer: S) -> std::result::Result<S::Ok, S::Error> {
+        if serializer.is_human_readable() {
+            serializer.serialize_str(&hex::encode(&self.0))
+        } else {
+            serializer.serialize_bytes(&self.0)
+        }
+    }
+}
+impl<'de> Deserialize<'de> for Bytes {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> std::result::Result<Self, D::Error> {
+        if deserializer.is_human_readable() {
+            let s = String::deserialize(deserializer)?;
+            hex::decode(s).map(Self).map_err(serde::de::Error::custom)
+        } else {
+            Vec::<u8>::deserialize(deserializer).map(Self)
+        }
+    }
+}
+
+impl From<FactorSourceKind> for FactorSourceKindSnapshot {
+    fn from(value: FactorSourceKind) -> Self {
+        match value {
+            FactorSourceKind::Ledger => Self::Ledger,
+            FactorSourceKind::Arculus => Self::Arculus,
+            FactorSourceKind::OffDeviceMnemonic => Self::OffDeviceMnemonic,
+            FactorSourceKind::Device => Self::Device,
+        }
+    }
+}
+impl From<FactorSourceKindSnapshot> for FactorSourceKind {
+    fn from(value: FactorSourceKindSnapshot) -> Self {
+        match value {
+            FactorSourceKindSnapshot::Ledger => Self::Ledger,
+            FactorSourceKindSnapshot::Arculus => Self::Arculus,
+            FactorSourceKindSnapshot::OffDeviceMnemonic => Self::OffDeviceMnemonic,
+            FactorSourceKindSnapshot::Device => Self::Device,
+        }
+    }
+}
+
+impl From<&FactorInstancesForEachNetworkCache> for CacheSnapshot {
+    fn from(value: &FactorInstancesForEachNetworkCache) -> Self {
+        let snapshot_of = |instances: IndexSet<HDFactorInstance>| {
+            instances
+                .into_iter()
+                .map(|f| InstanceSnapshot {
+                    derivation_path: f.derivation_path.to_string(),
+                    public_key: Bytes(f.public_key.to_bytes()),
+                })
+                .collect_vec()
+        };
+        let snapshot_of_any = |instances: &IndexSet<HDFactorInstance>| {
+            instances
+                .iter()
+                .map(|f| FactorInstanceSnapshot {
+                    kind: f.factor_source_id.kind.into(),
+                    body: Bytes(f.factor_source_id.body.to_vec()),
+                    derivation_path: f.derivation_path.to_string(),
+                    public_key: Bytes(f.public_key.to_bytes()),
+                })
+                .collect_vec()
+        };
+        let networks = value
+            .networks
+            .values()
+            .sorted_by_key(|c| c.network_id.discriminant())
+            .map(|on_network| NetworkSnapshot {
+                network_id: on_network.network_id.discriminant(),
+                stamp: on_network.version(),
+                factor_sources: on_network
+                    .all_factor_sources()
+                    .into_iter()
+                    .map(|(id, c)| {
+                        let instances = |t| snapshot_of(c.instances_for_template(t));
+                        FactorSourceSnapshot {
+                            kind: id.kind.into(),
+                            body: Bytes(id.body.to_vec()),
+                            unsecurified_accounts: instances(DerivationTemplate::AccountVeci),
+                            unsecurified_identities: instances(DerivationTemplate::IdentityVeci),
+                            securified_accounts: instances(DerivationTemplate::AccountMfa),
+                            securified_identities: instances(DerivationTemplate::IdentityMfa),
+                            securified_accounts_rola: instances(DerivationTemplate::AccountRola),
+                        }
+                    })
+                    .collect(),
+            })
+            .collect();
+        let leases = value
+            .leases
+            .values()
+            .map(|lease| LeaseSnapshot {
+                id: lease.id.0,
+                network_id: lease.network_id.discriminant(),
+                expires_at: lease
+                    .expires_at
+                    .duration_since(SystemTime::UNIX_EPOCH)
+                    .unwrap_or_default()
+                    .as_secs(),
+                instances: snapshot_of_any(&lease.instances),
+            })
+            .collect();
+        let pending_consumptions = value
+            .pending_consumptions
+            .iter()
+            .map(|p| PendingConsumptionSnapshot {
+                network_id: p.network_id.discriminant(),
+                instances: snapshot_of_any(&p.instances),
+            })
+            .collect();
+        Self {
+            version: CACHE_SERIALIZATION_VERSION,
+            networks,
+            next_lease_id: value.next_lease_id,
+            leases,
+            pending_consumptions,
+        }
+    }
+}
+
+impl TryFrom<CacheSnapshot> for FactorInstancesForEachNetworkCache {
+    type Error = CommonError;
+
+    /// Goes through the validating constructors of every type, so that an
+    /// invalid snapshot cannot result in an invalid cache.
+    fn try_from(value: CacheSnapshot) -> Result<Self> {
+        let mut cache = Self::default();
+        for network in value.networks {
+            let network_id = NetworkID::from_discriminant(network.network_id)?;
+            if cache.networks.contains_key(&network_id) {
+                return Err(CommonError::InvalidCacheEncoding);
+            }
+            let on_network = FactorInstancesForSpecificNetworkCache::empty(network_id);
+            for factor_source in network.factor_sources {
+                let body = factor_source
+                    .body
+                    .0
+                    .try_into()
+                    .map_err(|_| CommonError::InvalidCacheEncoding)?;
+                let factor_source_id = FactorSourceID::new(factor_source.kind.into(), body);
+                if on_network
+                    .peek_all_instances_for_factor_source(factor_source_id)
+                    .is_some()
+                {
+                    return Err(CommonError::InvalidCacheEncoding);
+                }
+                let instances = |snapshots: Vec<InstanceSnapshot>| {
+                    snapshots
+                        .into_iter()
+                        .map(|s| {
+                            let public_key = PublicKey::from_bytes_on_curve(
+                                &s.public_key.0,
+                                factor_source_id.kind.curve(),
+                            )?;
+                            HDFactorInstance::new(
+                                s.derivation_path.parse()?,
+                                factor_source_id,
+                                public_key,
+                            )
+                        })
+                        .collect::<Result<IndexSet<_>>>()
+                };
+                let collections = CollectionsOfFactorInstances::with_instances(
+                    network_id,
+                    factor_source_id,
+                    IndexMap::from_iter([
+                        (
+                            DerivationTemplate::AccountVeci,
+                            instances(factor_source.unsecurified_accounts)?,
+                        ),
+                        (
+                            DerivationTemplate::IdentityVeci,
+                            instances(factor_source.unsecurified_identities)?,
+                        ),
+                        (
+                            DerivationTemplate::AccountMfa,
+                            instances(factor_source.securified_accounts)?,
+                        ),
+                        (
+                            DerivationTemplate::IdentityMfa,
+                            instances(factor_source.securified_identities)?,
+                        ),
+                        (
+                            DerivationTemplate::AccountRola,
+                            instances(factor_source.securified_accounts_rola)?,
+                        ),
+                    ]),
+                )?;
+                on_network.append_for_factor(factor_source_id, ToCache(collections))?;
+            }
+            cache
+                .networks
+                .insert(network_id, on_network.with_version(network.stamp));
+        }
+        for lease in value.leases {
+            let id = LeaseID(lease.id);
+            if id.0 >= value.next_lease_id || cache.leases.contains_key(&id) {
+                return Err(CommonError::InvalidCacheEncoding);
+            }
+            let network_id = NetworkID::from_discriminant(lease.network_id)?;
+            let instances = instances_on_network(network_id, lease.instances)?;
+            let expires_at = SystemTime::UNIX_EPOCH + Duration::from_secs(lease.expires_at);
+            cache.leases.insert(
+                id,
+                FactorInstancesLease::new(id, network_id, instances, expires_at),
+            );
+        }
+        cache.next_lease_id = value.next_lease_id;
+        for pending in value.pending_consumptions {
+            let network_id = NetworkID::from_discriminant(pending.network_id)?;
+            let instances = instances_on_network(network_id, pending.instances)?;
+            cache.journal_consumption(network_id, instances);
+        }
+        Ok(cache)
+    }
+}
+
+/// Fails if any instance is invalid or not on `network_id`.
+fn instances_on_network(
+    network_id: NetworkID,
+    snapshots: Vec<FactorInstanceSnapshot>,
+) -> Result<IndexSet<HDFactorInstance>> {
+    snapshots
+        .into_iter()
+        .map(|s| {
+            let body = s
+                .body
+                .0
+                .try_into()
+                .map_err(|_| CommonError::InvalidCacheEncoding)?;
+            let factor_source_id = FactorSourceID::new(s.kind.into(), body);
+            let derivation_path = s.derivation_path.parse::<DerivationPath>()?;
+            if derivation_path.network_id != network_id {
+                return Err(CommonError::NetworkDiscrepancy {
+                    expected: network_id,
+                    found: derivation_path.network_id,
+                });
+            }
+            let public_key =
+                PublicKey::from_bytes_on_curve(&s.public_key.0, factor_source_id.kind.curve())?;
+            HDFactorInstance::new(derivation_path, factor_source_id, public_key)
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    type Sut = FactorInstancesForEachNetworkCache;
+
+    fn sample() -> Sut {
+        let mut sut = Sut::default();
+        for network_id in NetworkID::all() {
+            let on_network = FactorInstancesForSpecificNetworkCache::empty(network_id);
+            for factor_source in [HDFactorSource::sample(), HDFactorSource::sample_other()] {
+                let id = factor_source.factor_source_id;
+                let per_template = DerivationTemplate::all()
+                    .into_iter()
+                    .map(|t| {
+                        let paths = (0..2)
+                            .map(|i| t.derivation_path(network_id, i).unwrap())
+                            .collect();
+                        (t, factor_source.derive(&paths).unwrap())
+                    })
+                    .collect();
+                on_network
+                    .append_for_factor(
+                        id,
+                        ToCache(
+                            CollectionsOfFactorInstances::with_instances(
+                                network_id,
+                                id,
+                                per_template,
+                            )
+                            .unwrap(),
+                        ),
+                    )
+                    .unwrap();
+            }
+            sut.merge(on_network).unwrap();
+        }
+        let leased = HDFactorSource::sample()
+            .derive(&IndexSet::from_iter([DerivationTemplate::AccountVeci
+                .derivation_path(NetworkID::Mainnet, 2)
+                .unwrap()]))
+            .unwrap();
+        sut.lease(
+            NetworkID::Mainnet,
+            leased,
+            SystemTime::UNIX_EPOCH + Duration::from_secs(1_700_000_000),
+        );
+        let pending = HDFactorSource::sample_other()
+            .derive(&IndexSet::from_iter([DerivationTemplate::IdentityVeci
+                .derivation_path(NetworkID::Testnet, 2)
+                .unwrap()]))
+            .unwrap();
+        sut.journal_consumption(NetworkID::Testnet, pending);
+        sut
+    }
+
+    fn assert_same(lhs: &Sut, rhs: &Sut) {
+        for network_id in NetworkID::all() {
+            assert_eq!(
+                lhs.clone_for_network(network_id).map(|c| c.version()),
+                rhs.clone_for_network(network_id).map(|c| c.version())
+            );
+            assert_eq!(
+                lhs.clone_for_network(network_id)
+                    .map(|c| c.all_factor_sources()),
+                rhs.clone_for_network(network_id)
+                    .map(|c| c.all_factor_sources())
+            );
+        }
+        assert_eq!(lhs.leases, rhs.leases);
+        assert_eq!(lhs.next_lease_id, rhs.next_lease_id);
+        assert_eq!(lhs.pending_consumptions, rhs.pending_consumptions);
+    }
+
+    #[test]
+    fn json_roundtrip() {
+        let sut = sample();
+        let json = sut.to_json();
+        assert!(json.contains("\"derivation_path\": \"m/44H/1022H/1H/525H/1460H/0H\""));
+        assert_same(&Sut::from_json(&json).unwrap(), &sut);
+    }
+
+    #[test]
+    fn bytes_roundtrip() {
+        let sut = sample();
+        let bytes = sut.to_bytes();
+        assert!(bytes.len() < sut.to_json().len() / 2);
+        assert_same(&Sut::from_bytes(&bytes).unwrap(), &sut);
+    }
+
+    #[test]
+    fn unsupported_version_is_err() {
+        let json = sample()
+            .to_json()
+            .replacen("\"version\": 4", "\"version\": 5", 1);
+        assert_eq!(
+            Sut::from_json(&json).err(),
+            Some(CommonError::UnsupportedCacheVersion { found: 5 })
+        );
+        let mut bytes = sample().to_bytes();
+        bytes[0] = 9;
+        assert_eq!(
+            Sut::from_bytes(&bytes).err(),
+            Some(CommonError::UnsupportedCacheVersion { found: 9 })
+        );
+    }
+
+    /// One account veci of `HDFactorSource::sample` on mainnet, before leases
+    /// and the journal.
+    const V2_FIXTURE: &str = r#"{
+  "version": 2,
+  "networks": [
+    {
+      "network_id": 1,
+      "stamp": 1,
+      "factor_sources": [
+        {
+          "kind": "device",
+          "body": "aaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaa",
+          "unsecurified_accounts": [
+            {
+              "derivation_path": "m/44H/1022H/1H/525H/1460H/0H",
+              "public_key": "a5f40a306cd319049700e1dbbe8e446d17146926534e389ee9365f506549678d"
+            }
+          ],
+          "unsecurified_identities": [],
+          "securified_accounts": [],
+          "securified_identities": [],
+          "securified_accounts_rola": []
+        }
+      ]
+    }
+  ]
+}"#;
+
+    fn assert_v2_fixture(sut: &Sut, stamp: u64) {
+        let on_mainnet = sut.clone_for_network(NetworkID::Mainnet).unwrap();
+        assert_eq!(on_mainnet.version(), stamp);
+        assert_eq!(
+            on_mainnet
+                .peek_all_instances_for_factor_source(FactorSourceID::sample())
+                .unwrap()
+                .unsecurified_accounts()
+                .iter()
+                .map(|f| f.derivation_entity_index())
+                .collect_vec(),
+            vec![CAP26EntityIndex::Unsecurified(0)]
+        );
+        assert!(sut.leases.is_empty());
+        assert_eq!(sut.next_lease_id, 0);
+        assert!(sut.pending_consumptions.is_empty());
+    }
+
+    #[test]
+    fn version_2_is_migrated() {
+        let sut = Sut::from_json(V2_FIXTURE).unwrap();
+        assert_v2_fixture(&sut, 1);
+
+        // Same as v4, without the trailing `next_lease_id` and empty
+        // `leases` and `pending_consumptions`.
+        let mut bytes = sut.to_bytes();
+        bytes.truncate(bytes.len() - 3 * 8);
+        bytes[0] = 2;
+        assert_v2_fixture(&Sut::from_bytes(&bytes).unwrap(), 1);
+    }
+
+    #[test]
+    fn version_1_is_migrated() {
+        let json = V2_FIXTURE
+            .replacen("\"version\": 2", "\"version\": 1", 1)
+            .replacen("\"stamp\": 1,", "", 1);
+        let sut = Sut::from_json(&json).unwrap();
+        assert_v2_fixture(&sut, 0);
+
+        // Same as v4, without the trailing `next_lease_id` and empty `leases`
+        // and `pending_consumptions`, nor the `stamp` of the only network,
+        // after the version, the length of `networks` and its `network_id`.
+        let mut bytes = sut.to_bytes();
+        bytes.truncate(bytes.len() - 3 * 8);
+        bytes.drain(16..24);
+        bytes[0] = 1;
+        assert_v2_fixture(&Sut::from_bytes(&bytes).unwrap(), 0);
+    }
+
+    /// `V2_FIXTURE` with a lease of the next account veci, before the journal.
+    const V3_FIXTURE: &str = r#"{
+  "version": 3,
+  "networks": [
+    {
+      "network_id": 1,
+      "stamp": 1,
+      "factor_sources": [
+        {
+          "kind": "device",
+          "body": "aaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaa",
+          "unsecurified_accounts": [
+            {
+              "derivation_path": "m/44H/1022H/1H/525H/1460H/0H",
+              "public_key": "a5f40a306cd319049700e1dbbe8e446d17146926534e389ee9365f506549678d"
+            }
+          ],
+          "unsecurified_identities": [],
+          "securified_accounts": [],
+          "securified_identities": [],
+          "securified_accounts_rola": []
+        }
+      ]
+    }
+  ],
+  "next_lease_id": 1,
+  "leases": [
+    {
+      "id": 0,
+      "network_id": 1,
+      "expires_at": 1700000000,
+      "instances": [
+        {
+          "kind": "device",
+          "body": "aaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaa",
+          "derivation_path": "m/44H/1022H/1H/525H/1460H/1H",
+          "public_key": "c1113a28db03caa752b45d3d0e91011d6adbc6e94d9b5bafccd7871729ade306"
+        }
+      ]
+    }
+  ]
+}"#;
+
+    fn assert_v3_fixture(sut: &Sut) {
+        let lease = sut.leases.get(&LeaseID(0)).unwrap();
+        assert_eq!(lease.network_id, NetworkID::Mainnet);
+        assert_eq!(
+            lease.expires_at,
+            SystemTime::UNIX_EPOCH + Duration::from_secs(1_700_000_000)
+        );
+        assert_eq!(
+            lease
+                .instances
+                .iter()
+                .map(|f| f.derivation_path)
+                .collect_vec(),
+            vec![DerivationTemplate::AccountVeci
+                .derivation_path(NetworkID::Mainnet, 1)
+                .unwrap()]
+        );
+        assert_eq!(sut.next_lease_id, 1);
+        assert!(sut.pending_consumptions.is_empty());
+    }
+
+    #[test]
+    fn version_3_is_migrated() {
+        let sut = Sut::from_json(V3_FIXTURE).unwrap();
+        assert_v3_fixture(&sut);
+
+        // Same as v4, without the trailing empty `pending_consumptions`.
+        let mut bytes = sut.to_bytes();
+        bytes.truncate(bytes.len() - 8);
+        bytes[0] = 3;
+        assert_v3_fixture(&Sut::from_bytes(&bytes).unwrap());
+    }
+
+    #[test]
+    fn mistyped_instance_is_err() {
+        // An account veci in the set of securified accounts
+        let json = sample().to_json().replacen(
+            "m/44H/1022H/1H/525H/1460H/0S",
+            "m/44H/1022H/1H/525H/1460H/0H",
+            1,
+        );
+        assert_eq!(
+            Sut::from_json(&json).err(),
+            Some(CommonError::KeySpaceDiscrepancy {
+                factor_source_id: FactorSourceID::sample(),
+                derivation_path: DerivationTemplate::AccountVeci
+                    .derivation_path(NetworkID::Mainnet, 0)
+                    .unwrap(),
+                expected: KeySpace::Securified,
+                found: KeySpace::Unsecurified,
+            })
+        );
+    }
+}
@@ -0,0 +1,202 @@
+use crate::prelude::*;
+
+/// The instances evicted from the cache, per factor source.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct FactorSourceEviction {
+    pub evicted: IndexMap<FactorSourceID, IndexSet<HDFactorInstance>>,
+}
+impl FactorSourceEviction {
+    pub fn is_empty(&self) -> bool {
+        self.evicted.is_empty()
+    }
+    pub fn evicted_factor_sources(&self) -> IndexSet<FactorSourceID> {
+        self.evicted.keys().copied().collect()
+    }
+}
+
+impl FactorInstancesForEachNetworkCache {
+    /// Drops every instance of `factor_source_id`, on every network - also
+    /// from leases and pending consumptions, so that they are never put back
+    /// in the cache - returning them.
+    pub fn evict_factor_source(
+        &mut self,
+        factor_source_id: FactorSourceID,
+    ) -> Result<IndexSet<HDFactorInstance>> {
+        let mut evicted = IndexSet::new();
+        let network_ids = self
+            .networks
+            .keys()
+            .copied()
+            .sorted_by_key(|n| n.discriminant())
+            .collect_vec();
+        for network_id in network_ids {
+            let on_network = self.clone_for_network_or_empty(network_id);
+            let Some(removed) = on_network.remove_factor_source(factor_source_id) else {
+                continue;
+            };
+            evicted.extend(
+                DerivationTemplate::all()
+                    .into_iter()
+                    .flat_map(|t| removed.instances_for_template(t)),
+            );
+            self.merge(on_network)?;
+        }
+        let outstanding = self.leases.values_mut().map(|l| &mut l.instances).chain(
+            self.pending_consumptions
+                .iter_mut()
+                .map(|p| &mut p.instances),
+        );
+        for instances in outstanding {
+            let (removed, kept) = instances
+                .drain(..)
+                .partition::<IndexSet<_>, _>(|f| f.factor_source_id == factor_source_id);
+            *instances = kept;
+            evicted.extend(removed);
+        }
+        self.leases.retain(|_, l| !l.instances.is_empty());
+        self.pending_consumptions
+            .retain(|p| !p.instances.is_empty());
+        Ok(evicted)
+    }
+
+    /// Evicts every factor source which `profile` no longer references, i.e.
+    /// which the user has deleted, reporting what was evicted.
+    pub fn sweep_unreferenced_factor_sources(
+        &mut self,
+        profile: &Profile,
+    ) -> Result<FactorSourceEviction> {
+        let unreferenced = self
+            .networks
+            .values()
+            .sorted_by_key(|c| c.network_id.discriminant())
+            .flat_map(|c| c.all_factor_sources().into_keys())
+            .chain(
+                self.leases
+                    .values()
+                    .flat_map(|l| l.instances.iter())
+                    .chain(
+                        self.pending_consumptions
+                            .iter()
+                            .flat_map(|p| p.instances.iter()),
+                    )
+                    .map(|f| f.factor_source_id),
+            )
+            .filter(|id| !profile.references_factor_source(*id))
+            .collect::<IndexSet<_>>();
+        let mut eviction = FactorSourceEviction::default();
+        for factor_source_id in unreferenced {
+            let evicted = self.evict_factor_source(factor_source_id)?;
+            eviction.evicted.insert(factor_source_id, evicted);
+        }
+        Ok(eviction)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::{Duration, SystemTime};
+
+    use super::*;
+
+    type Sut = FactorInstancesForEachNetworkCache;
+
+    /// Two instances per template for both factor sources on every network,
+    /// and a lease of an instance of `HDFactorSource::sample()`.
+    fn sample() -> Sut {
+        let mut sut = Sut::default();
+        for network_id in NetworkID::all() {
+            let on_network = FactorInstancesForSpecificNetworkCache::empty(network_id);
+            for factor_source in [HDFactorSource::sample(), HDFactorSource::sample_other()] {
+                let id = factor_source.factor_source_id;
+                let per_template = DerivationTemplate::all()
+                    .into_iter()
+                    .map(|t| {
+                        let paths = (0..2)
+                            .map(|i| t.derivation_path(network_id, i).unwrap())
+                            .collect();
+                        (t, factor_source.derive(&paths).unwrap())
+                    })
+                    .collect();
+                on_network
+                    .append_for_factor(
+                        id,
+                        ToCache(
+                            CollectionsOfFactorInstances::with_instances(
+                                network_id,
+                                id,
+                                per_template,
+                            )
+                            .unwrap(),
+                        ),
+                    )
+                    .unwrap();
+            }
+            sut.merge(on_network).unwrap();
+        }
+        let leased = HDFactorSource::sample()
+            .derive(&IndexSet::from_iter([DerivationTemplate::AccountVeci
+                .derivation_path(NetworkID::Mainnet, 2)
+                .unwrap()]))
+            .unwrap();
+        sut.lease(
+            NetworkID::Mainnet,
+            leased,
+            SystemTime::now() + Duration::from_secs(60),
+        );
+        sut
+    }
+
+    #[test]
+    fn evict_factor_source_on_every_network() {
+        let mut sut = sample();
+
+        let evicted = sut.evict_factor_source(FactorSourceID::sample()).unwrap();
+
+        assert_eq!(evicted.len(), 2 * 5 * 2 + 1);
+        assert!(evicted
+            .iter()
+            .all(|f| f.factor_source_id == FactorSourceID::sample()));
+        for network_id in NetworkID::all() {
+            let on_network = sut.clone_for_network(network_id).unwrap();
+            assert!(on_network
+                .peek_all_instances_for_factor_source(FactorSourceID::sample())
+                .is_none());
+            assert!(on_network
+                .peek_all_instances_for_factor_source(FactorSourceID::sample_other())
+                .is_some());
+        }
+        assert!(sut.leases.is_empty());
+        assert!(sut
+            .evict_factor_source(FactorSourceID::sample())
+            .unwrap()
+            .is_empty());
+    }
+
+    #[test]
+    fn sweep_evicts_factor_sources_no_longer_in_profile() {
+        let mut sut = sample();
+        let profile = Profile {
+            factor_sources: IndexSet::from_iter([FactorSourceID::sample_other()]),
+            ..Profile::default()
+        };
+
+        let eviction = sut.sweep_unreferenced_factor_sources(&profile).unwrap();
+
+        assert_eq!(
+            eviction.evicted_factor_sources(),
+            IndexSet::<FactorSourceID>::from_iter([FactorSourceID::sample()])
+        );
+        assert_eq!(
+            sut.clone_for_network(NetworkID::Mainnet)
+                .unwrap()
+                .all_factor_sources()
+                .into_keys()
+                .collect_vec(),
+            vec![FactorSourceID::sample_other()]
+        );
+        assert!(sut
+            .sweep_unreferenced_factor_sources(&profile)
+            .unwrap()
+            .is_empty());
+    }
+}
@@ -0,0 +1,182 @@
+use crate::prelude::*;
+
+/// Instances provided to be used in Profile - taken out of the cache or newly
+/// derived - journaled in the same atomic update of the cache which consumed
+/// them, so that they are not lost if the host crashes before saving Profile.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct PendingConsumption {
+    hidden_constructor: HiddenConstructor,
+    pub network_id: NetworkID,
+    pub instances: IndexSet<HDFactorInstance>,
+}
+impl PendingConsumption {
+    pub fn new(network_id: NetworkID, instances: IndexSet<HDFactorInstance>) -> Self {
+        Self {
+            hidden_constructor: HiddenConstructor,
+            network_id,
+            instances,
+        }
+    }
+}
+
+/// The outcome of reconciling the journal of the cache with Profile.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct JournalReconciliation {
+    /// Instances which ended up in Profile, forgotten.
+    pub dropped: IndexSet<HDFactorInstance>,
+    /// Orphaned instances, not in Profile, put back in the cache.
+    pub restored: IndexSet<HDFactorInstance>,
+}
+
+impl FactorInstancesForEachNetworkCache {
+    /// Journals `instances` as pending until reconciled with Profile.
+    pub fn journal_consumption(
+        &mut self,
+        network_id: NetworkID,
+        instances: IndexSet<HDFactorInstance>,
+    ) {
+        if instances.is_empty() {
+            return;
+        }
+        self.pending_consumptions
+            .push(PendingConsumption::new(network_id, instances));
+    }
+
+    /// Reconciles every pending consumption and every lease with `profile`,
+    /// which MUST be the saved Profile: instances in it are dropped from the
+    /// journal, the orphaned ones are put back in the cache.
+    ///
+    /// MUST only be called when no instances are in flight, e.g. at startup.
+    pub fn reconcile_with_profile(&mut self, profile: &Profile) -> Result<JournalReconciliation> {
+        let in_profile = profile.all_factor_instances();
+        let pending = self
+            .pending_consumptions
+            .drain(..)
+            .map(|p| (p.network_id, p.instances))
+            .chain(
+                self.leases
+                    .drain(..)
+                    .map(|(_, l)| (l.network_id, l.instances)),
+            )
+            .collect_vec();
+        let mut reconciliation = JournalReconciliation::default();
+        for (network_id, instances) in pending {
+            let (dropped, restored): (IndexSet<_>, IndexSet<_>) =
+                instances.into_iter().partition(|f| in_profile.contains(f));
+            if !restored.is_empty() {
+                let on_network = self.clone_for_network_or_empty(network_id);
+                on_network.put_back(restored.clone())?;
+                self.merge(on_network)?;
+            }
+            reconciliation.dropped.extend(dropped);
+            reconciliation.restored.extend(restored);
+        }
+        Ok(reconciliation)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{sync::Arc, time::Duration};
+
+    use super::*;
+
+    fn query() -> InstancesQuery {
+        InstancesQuery::AccountVeci {
+            factor_source: HDFactorSource::sample(),
+        }
+    }
+
+    fn profile_with_account(instance: HDFactorInstance) -> Profile {
+        let network_id = instance.derivation_path.network_id;
+        Profile {
+            factor_sources: IndexSet::new(),
+            networks: IndexMap::from_iter([(
+                network_id,
+                ProfileOnNetwork {
+                    network_id,
+                    accounts: IndexSet::from_iter([Account::new(
+                        EntitySecurityState::Unsecurified(instance),
+                    )]),
+                    personas: IndexSet::new(),
+                },
+            )]),
+        }
+    }
+
+    #[actix::test]
+    async fn recover_drops_used_and_restores_orphaned_instances() {
+        let storage = Arc::new(InMemoryFactorInstancesCacheStorage::default());
+        let provide = || {
+            FactorInstancesProvider::provide(
+                storage.clone(),
+                NetworkID::Mainnet,
+                Profile::default(),
+                query(),
+                KeysDerivationInteractors::test(),
+            )
+        };
+        let saved = provide().await.unwrap().account_veci().unwrap().instance();
+        let orphaned = provide().await.unwrap().account_veci().unwrap().instance();
+        assert_eq!(storage.load().await.unwrap().pending_consumptions.len(), 2);
+
+        let reconciliation =
+            FactorInstancesProvider::recover(storage.as_ref(), profile_with_account(saved.clone()))
+                .await
+                .unwrap();
+
+        assert_eq!(
+            reconciliation.dropped,
+            IndexSet::<HDFactorInstance>::from_iter([saved])
+        );
+        assert_eq!(
+            reconciliation.restored,
+            IndexSet::<HDFactorInstance>::from_iter([orphaned.clone()])
+        );
+        let cache = storage.load().await.unwrap();
+        assert!(cache.pending_consumptions.is_empty());
+        assert_eq!(
+            cache
+                .clone_for_network(NetworkID::Mainnet)
+                .unwrap()
+                .peek_all_instances_for_factor_source(FactorSourceID::sample())
+                .unwrap()
+                .unsecurified_accounts()
+                .first()
+                .map(|f| f.instance()),
+            Some(orphaned)
+        );
+    }
+
+    #[actix::test]
+    async fn recover_reconciles_leases() {
+        let storage = Arc::new(InMemoryFactorInstancesCacheStorage::default());
+        let lease = FactorInstancesProvider::provide_leased(
+            storage.clone(),
+            NetworkID::Mainnet,
+            Profile::default(),
+            query(),
+            KeysDerivationInteractors::test(),
+            Duration::from_secs(60),
+        )
+        .await
+        .unwrap();
+        let saved = lease.instances.first().unwrap().clone();
+
+        let reconciliation =
+            FactorInstancesProvider::recover(storage.as_ref(), profile_with_account(saved.clone()))
+                .await
+                .unwrap();
+
+        assert_eq!(
+            reconciliation.dropped,
+            IndexSet::<HDFactorInstance>::from_iter([saved])
+        );
+        assert!(reconciliation.restored.is_empty());
+        assert!(storage.load().await.unwrap().leases.is_empty());
+        assert_eq!(
+            lease.commit(storage.as_ref()).await,
+            Err(CommonError::UnknownLease)
+        );
+    }
+}
@@ -0,0 +1,293 @@
+use std::time::SystemTime;
+
+use crate::prelude::*;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash, PartialOrd, Ord)]
+pub struct LeaseID(pub u64);
+
+/// Instances taken out of the cache and handed to the caller, reserved until
+/// they are committed - once used in Profile - or released - i.e. put back in
+/// the cache, e.g. if the user cancelled - or until the lease expires, after
+/// which they are put back in the cache automatically.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct FactorInstancesLease {
+    hidden_constructor: HiddenConstructor,
+    pub id: LeaseID,
+    pub network_id: NetworkID,
+    pub instances: IndexSet<HDFactorInstance>,
+    pub expires_at: SystemTime,
+}
+impl FactorInstancesLease {
+    pub fn new(
+        id: LeaseID,
+        network_id: NetworkID,
+        instances: IndexSet<HDFactorInstance>,
+        expires_at: SystemTime,
+    ) -> Self {
+        Self {
+            hidden_constructor: HiddenConstructor,
+            id,
+            network_id,
+            instances,
+            expires_at,
+        }
+    }
+
+    pub fn is_expired(&self, now: SystemTime) -> bool {
+        now >= self.expires_at
+    }
+
+    /// The instances are used in Profile, so the lease is forgotten and the
+    /// instances never put back in the cache.
+    pub async fn commit(&self, storage: &impl FactorInstancesCacheStorage) -> Result<()> {
+        let id = self.id;
+        storage
+            .update(Box::new(move |cache| cache.commit_lease(id)))
+            .await
+    }
+
+    /// The instances will not be used, so they are put back in the cache.
+    pub async fn release(&self, storage: &impl FactorInstancesCacheStorage) -> Result<()> {
+        let id = self.id;
+        storage
+            .update(Box::new(move |cache| cache.release_lease(id)))
+            .await
+    }
+}
+
+impl FactorInstancesForEachNetworkCache {
+    /// Registers a lease of `instances` - which MUST already be taken out of
+    /// the cache - until `expires_at`.
+    pub fn lease(
+        &mut self,
+        network_id: NetworkID,
+        instances: IndexSet<HDFactorInstance>,
+        expires_at: SystemTime,
+    ) -> FactorInstancesLease {
+        let id = LeaseID(self.next_lease_id);
+        self.next_lease_id += 1;
+        let lease = FactorInstancesLease::new(id, network_id, instances, expires_at);
+        self.leases.insert(id, lease.clone());
+        lease
+    }
+
+    pub fn commit_lease(&mut self, id: LeaseID) -> Result<()> {
+        self.leases
+            .shift_remove(&id)
+            .map(|_| ())
+            .ok_or(CommonError::UnknownLease)
+    }
+
+    /// Puts the instances of the lease back in the cache, in index order.
+    pub fn release_lease(&mut self, id: LeaseID) -> Result<()> {
+        let lease = self.leases.get(&id).ok_or(CommonError::UnknownLease)?;
+        let on_network = self.clone_for_network_or_empty(lease.network_id);
+        on_network.put_back(lease.instances.clone())?;
+        self.merge(on_network)?;
+        self.leases.shift_remove(&id);
+        Ok(())
+    }
+
+    /// Reclaims every lease which has expired at `now`, returning their IDs:
+    /// the instances used in `profile` are dropped - as if the lease was
+    /// committed - the others are returned to the cache.
+    pub fn reclaim_expired_leases(
+        &mut self,
+        now: SystemTime,
+        profile: &Profile,
+    ) -> Result<IndexSet<LeaseID>> {
+        let in_profile = profile.all_factor_instances();
+        let expired = self
+            .leases
+            .values()
+            .filter(|l| l.is_expired(now))
+            .cloned()
+            .collect_vec();
+        for lease in expired.iter() {
+            let unused = lease
+                .instances
+                .iter()
+                .filter(|f| !in_profile.contains(*f))
+                .cloned()
+                .collect::<IndexSet<_>>();
+            let on_network = self.clone_for_network_or_empty(lease.network_id);
+            on_network.return_instances(unused, profile)?;
+            self.merge(on_network)?;
+            self.leases.shift_remove(&lease.id);
+        }
+        Ok(expired.into_iter().map(|l| l.id).collect())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{sync::Arc, time::Duration};
+
+    use super::*;
+
+    type Sut = FactorInstancesLease;
+
+    async fn lease_account_veci_in(
+        storage: Arc<InMemoryFactorInstancesCacheStorage>,
+        profile: Profile,
+        lease_duration: Duration,
+    ) -> Result<Sut> {
+        FactorInstancesProvider::provide_leased(
+            storage,
+            NetworkID::Mainnet,
+            profile,
+            InstancesQuery::AccountVeci {
+                factor_source: HDFactorSource::sample(),
+            },
+            KeysDerivationInteractors::test(),
+            lease_duration,
+        )
+        .await
+    }
+
+    async fn lease_account_veci(
+        storage: Arc<InMemoryFactorInstancesCacheStorage>,
+        lease_duration: Duration,
+    ) -> Sut {
+        lease_account_veci_in(storage, Profile::default(), lease_duration)
+            .await
+            .unwrap()
+    }
+
+    fn first_index(lease: &Sut) -> CAP26EntityIndex {
+        lease
+            .instances
+            .first()
+            .unwrap()
+            .derivation_path
+            .entity_index
+    }
+
+    #[actix::test]
+    async fn released_instances_are_provided_again() {
+        let storage = Arc::new(InMemoryFactorInstancesCacheStorage::default());
+        let sut = lease_account_veci(storage.clone(), Duration::from_secs(60)).await;
+        assert_eq!(first_index(&sut), CAP26EntityIndex::Unsecurified(0));
+
+        sut.release(storage.as_ref()).await.unwrap();
+
+        let cache = storage.load().await.unwrap();
+        assert!(cache.leases.is_empty());
+        assert_eq!(
+            cache
+                .clone_for_network(NetworkID::Mainnet)
+                .unwrap()
+                .peek_all_instances_for_factor_source(FactorSourceID::sample())
+                .unwrap()
+                .unsecurified_accounts()
+                .first()
+                .map(|f| f.derivation_entity_index()),
+            Some(CAP26EntityIndex::Unsecurified(0))
+        );
+        let again = lease_account_veci(storage, Duration::from_secs(60)).await;
+        assert_eq!(first_index(&again), CAP26EntityIndex::Unsecurified(0));
+    }
+
+    #[actix::test]
+    async fn committed_lease_is_forgotten() {
+        let storage = Arc::new(InMemoryFactorInstancesCacheStorage::default());
+        let sut = lease_account_veci(storage.clone(), Duration::from_secs(60)).await;
+
+        sut.commit(storage.as_ref()).await.unwrap();
+
+        assert_eq!(
+            sut.commit(storage.as_ref()).await,
+            Err(CommonError::UnknownLease)
+        );
+        assert_eq!(
+            sut.release(storage.as_ref()).await,
+            Err(CommonError::UnknownLease)
+        );
+        let next = lease_account_veci(storage, Duration::from_secs(60)).await;
+        assert_eq!(first_index(&next), CAP26EntityIndex::Unsecurified(1));
+    }
+
+    #[actix::test]
+    async fn expired_lease_is_reclaimed() {
+        let storage = Arc::new(InMemoryFactorInstancesCacheStorage::default());
+        let expired = lease_account_veci(storage.clone(), Duration::ZERO).await;
+
+        let next = lease_account_veci(storage.clone(), Duration::from_secs(60)).await;
+
+        assert_eq!(first_index(&next), first_index(&expired));
+        assert_eq!(
+            storage.load().await.unwrap().leases.keys().collect_vec(),
+            vec![&next.id]
+        );
+        assert_eq!(
+            expired.commit(storage.as_ref()).await,
+            Err(CommonError::UnknownLease)
+        );
+    }
+
+    #[actix::test]
+    async fn expired_lease_is_kept_without_profile() {
+        let storage = Arc::new(InMemoryFactorInstancesCacheStorage::default());
+        let expired = lease_account_veci(storage.clone(), Duration::ZERO).await;
+
+        let next = FactorInstancesProvider::provide_leased(
+            storage.clone(),
+            NetworkID::Mainnet,
+            None,
+            InstancesQuery::AccountVeci {
+                factor_source: HDFactorSource::sample(),
+            },
+            KeysDerivationInteractors::test(),
+            Duration::from_secs(60),
+        )
+        .await
+        .unwrap();
+
+        assert_ne!(first_index(&next), first_index(&expired));
+        assert_eq!(
+            storage.load().await.unwrap().leases.keys().collect_vec(),
+            vec![&expired.id, &next.id]
+        );
+    }
+
+    #[actix::test]
+    async fn expired_lease_used_in_profile_is_dropped() {
+        let storage = Arc::new(InMemoryFactorInstancesCacheStorage::default());
+        let expired = lease_account_veci(storage.clone(), Duration::ZERO).await;
+        let profile = Profile {
+            factor_sources: IndexSet::from_iter([FactorSourceID::sample()]),
+            networks: IndexMap::from_iter([(
+                NetworkID::Mainnet,
+                ProfileOnNetwork {
+                    network_id: NetworkID::Mainnet,
+                    accounts: IndexSet::from_iter([Account::new(
+                        EntitySecurityState::Unsecurified(
+                            expired.instances.first().unwrap().clone(),
+                        ),
+                    )]),
+                    personas: IndexSet::new(),
+                },
+            )]),
+        };
+
+        let next = lease_account_veci_in(storage.clone(), profile, Duration::from_secs(60))
+            .await
+            .unwrap();
+
+        assert_eq!(first_index(&next), CAP26EntityIndex::Unsecurified(1));
+        assert_eq!(
+            storage.load().await.unwrap().leases.keys().collect_vec(),
+            vec![&next.id]
+        );
+    }
+
+    #[actix::test]
+    async fn lease_duration_overflowing_expiry_is_err() {
+        let storage = Arc::new(InMemoryFactorInstancesCacheStorage::default());
+        assert_eq!(
+            lease_account_veci_in(storage.clone(), Profile::default(), Duration::MAX).await,
+            Err(CommonError::LeaseDurationOverflow)
+        );
+        assert!(storage.load().await.unwrap().networks.is_empty());
+    }
+}
@@ -6,13 +6,16 @@ pub struct HiddenConstructor;
 pub trait IsHDFactorInstance {
     fn instance(&self) -> HDFactorInstance;
     fn derivation_path(&self) -> DerivationPath {
-        self.instance().derivation_path.clone()
+        self.instance().derivation_path
     }
     fn derivation_entity_index(&self) -> CAP26EntityIndex {
-        self.derivation_path().entity_index.clone()
+        self.derivation_path().entity_index
     }
     fn network_id(&self) -> NetworkID {
-        self.derivation_path().network_id.clone()
+        self.derivation_path().network_id
+    }
+    fn public_key(&self) -> PublicKey {
+        self.instance().public_key
     }
 }
 
@@ -30,20 +33,7 @@ impl IsHDFactorInstance for AccountVeci {
 }
 impl AccountVeci {
     pub fn new(instance: HDFactorInstance) -> Result<Self> {
-        let derivation_path = &instance.derivation_path;
-
-        if derivation_path.entity_kind != CAP26EntityKind::Account {
-            return Err(CommonError::EntityKindDiscrepancy);
-        }
-
-        if derivation_path.key_space() != KeySpace::Unsecurified {
-            return Err(CommonError::KeySpaceDiscrepancy);
-        }
-
-        if derivation_path.key_kind != CAP26KeyKind::TransactionSigning {
-            return Err(CommonError::KeyKindDiscrepancy);
-        }
-
+        DerivationTemplate::AccountVeci.ensure_matches(&instance)?;
         Ok(Self {
             hidden_constructor: HiddenConstructor,
             instance,
@@ -60,26 +50,80 @@ pub struct IdentityVeci {
 }
 impl IdentityVeci {
     pub fn new(instance: HDFactorInstance) -> Result<Self> {
-        let derivation_path = &instance.derivation_path;
-        if derivation_path.entity_kind != CAP26EntityKind::Identity {
-            return Err(CommonError::EntityKindDiscrepancy);
-        }
+        DerivationTemplate::IdentityVeci.ensure_matches(&instance)?;
+        Ok(Self {
+            hidden_constructor: HiddenConstructor,
+            instance,
+        })
+    }
+}
+impl IsHDFactorInstance for IdentityVeci {
+    fn instance(&self) -> HDFactorInstance {
+        self.instance.clone()
+    }
+}
 
-        if derivation_path.key_space() != KeySpace::Unsecurified {
-            return Err(CommonError::KeySpaceDiscrepancy);
-        }
+/// A FactorInstance with a derivation path that is used for
+/// Account, Securified, TransactionSigning
+#[derive(Clone, Debug, PartialEq, Eq, Hash)]
+pub struct AccountMfa {
+    hidden_constructor: HiddenConstructor,
+    instance: HDFactorInstance,
+}
+impl AccountMfa {
+    pub fn new(instance: HDFactorInstance) -> Result<Self> {
+        DerivationTemplate::AccountMfa.ensure_matches(&instance)?;
+        Ok(Self {
+            hidden_constructor: HiddenConstructor,
+            instance,
+        })
+    }
+}
+impl IsHDFactorInstance for AccountMfa {
+    fn instance(&self) -> HDFactorInstance {
+        self.instance.clone()
+    }
+}
 
-        if derivation_path.key_kind != CAP26KeyKind::TransactionSigning {
-            return Err(CommonError::KeyKindDiscrepancy);
-        }
+/// A FactorInstance with a derivation path that is used for
+/// Identity, Securified, TransactionSigning
+#[derive(Clone, Debug, PartialEq, Eq, Hash)]
+pub struct IdentityMfa {
+    hidden_constructor: HiddenConstructor,
+    instance: HDFactorInstance,
+}
+impl IdentityMfa {
+    pub fn new(instance: HDFactorInstance) -> Result<Self> {
+        DerivationTemplate::IdentityMfa.ensure_matches(&instance)?;
+        Ok(Self {
+            hidden_constructor: HiddenConstructor,
+            instance,
+        })
+    }
+}
+impl IsHDFactorInstance for IdentityMfa {
+    fn instance(&self) -> HDFactorInstance {
+        self.instance.clone()
+    }
+}
 
+/// A FactorInstance with a derivation path that is used for
+/// Account, Securified, AuthenticationSigning
+#[derive(Clone, Debug, PartialEq, Eq, Hash)]
+pub struct AccountRola {
+    hidden_constructor: HiddenConstructor,
+    instance: HDFactorInstance,
+}
+impl AccountRola {
+    pub fn new(instance: HDFactorInstance) -> Result<Self> {
+        DerivationTemplate::AccountRola.ensure_matches(&instance)?;
         Ok(Self {
             hidden_constructor: HiddenConstructor,
             instance,
         })
     }
 }
-impl IsHDFactorInstance for IdentityVeci {
+impl IsHDFactorInstance for AccountRola {
     fn instance(&self) -> HDFactorInstance {
         self.instance.clone()
     }
@@ -104,6 +148,86 @@ pub enum DerivationTemplate {
     /// Identity, Securified, TransactionSigning
     IdentityMfa,
 }
+impl DerivationTemplate {
+    pub fn all() -> IndexSet<Self> {
+        IndexSet::from_iter([
+            Self::AccountVeci,
+            Self::IdentityVeci,
+            Self::AccountRola,
+            Self::AccountMfa,
+            Self::IdentityMfa,
+        ])
+    }
+    pub fn entity_kind(&self) -> CAP26EntityKind {
+        match self {
+            Self::AccountVeci | Self::AccountRola | Self::AccountMfa => CAP26EntityKind::Account,
+            Self::IdentityVeci | Self::IdentityMfa => CAP26EntityKind::Identity,
+        }
+    }
+    pub fn key_space(&self) -> KeySpace {
+        match self {
+            Self::AccountVeci | Self::IdentityVeci => KeySpace::Unsecurified,
+            Self::AccountRola | Self::AccountMfa | Self::IdentityMfa => KeySpace::Securified,
+        }
+    }
+    pub fn key_kind(&self) -> CAP26KeyKind {
+        match self {
+            Self::AccountRola => CAP26KeyKind::AuthenticationSigning,
+            Self::AccountVeci | Self::IdentityVeci | Self::AccountMfa | Self::IdentityMfa => {
+                CAP26KeyKind::TransactionSigning
+            }
+        }
+    }
+    /// If `path` is a path of this template, on any network.
+    pub fn matches(&self, path: &DerivationPath) -> bool {
+        path.entity_kind == self.entity_kind()
+            && path.key_kind == self.key_kind()
+            && path.key_space() == self.key_space()
+    }
+    /// Fails with an error naming the instance, and what was expected
+    /// instead, unless the path of `instance` is a path of this template
+    /// with an entity index within its key space.
+    pub fn ensure_matches(&self, instance: &HDFactorInstance) -> Result<()> {
+        let factor_source_id = instance.factor_source_id;
+        let derivation_path = instance.derivation_path;
+        derivation_path.entity_index.validated()?;
+        if derivation_path.entity_kind != self.entity_kind() {
+            return Err(CommonError::EntityKindDiscrepancy {
+                factor_source_id,
+                derivation_path,
+                expected: self.entity_kind(),
+                found: derivation_path.entity_kind,
+            });
+        }
+        if derivation_path.key_space() != self.key_space() {
+            return Err(CommonError::KeySpaceDiscrepancy {
+                factor_source_id,
+                derivation_path,
+                expected: self.key_space(),
+                found: derivation_path.key_space(),
+            });
+        }
+        if derivation_path.key_kind != self.key_kind() {
+            return Err(CommonError::KeyKindDiscrepancy {
+                factor_source_id,
+                derivation_path,
+                expected: self.key_kind(),
+                found: derivation_path.key_kind,
+            });
+        }
+        Ok(())
+    }
+    /// The path of this template at `index` in its key space, fails if
+    /// `index` is not within the key space.
+    pub fn derivation_path(&self, network_id: NetworkID, index: u32) -> Result<DerivationPath> {
+        Ok(DerivationPath::new(
+            network_id,
+            self.entity_kind(),
+            self.key_kind(),
+            CAP26EntityIndex::try_new(self.key_space(), index)?,
+        ))
+    }
+}
 
 /// A collection of sets of FactorInstances,
 /// all on the same network
@@ -112,52 +236,271 @@ pub enum DerivationTemplate {
 #[derive(Clone, Debug, PartialEq, Eq)]
 pub struct CollectionsOfFactorInstances {
     hidden_constructor: HiddenConstructor,
-    pub network: NetworkID,
-    pub factor_source_id: FactorSourceID,
-    pub unsecurified_accounts: IndexSet<AccountVeci>,
-    pub unsecurified_identities: IndexSet<IdentityVeci>,
+    network: NetworkID,
+    factor_source_id: FactorSourceID,
+    unsecurified_accounts: IndexSet<AccountVeci>,
+    unsecurified_identities: IndexSet<IdentityVeci>,
+    securified_accounts: IndexSet<AccountMfa>,
+    securified_identities: IndexSet<IdentityMfa>,
+    securified_accounts_rola: IndexSet<AccountRola>,
 }
 impl CollectionsOfFactorInstances {
     pub fn empty(network: NetworkID, factor_source_id: FactorSourceID) -> Self {
-        Self::new(network, factor_source_id, IndexSet::new(), IndexSet::new()).unwrap()
+        Self::new(
+            network,
+            factor_source_id,
+            IndexSet::new(),
+            IndexSet::new(),
+            IndexSet::new(),
+            IndexSet::new(),
+            IndexSet::new(),
+        )
+        .unwrap()
+    }
+    pub fn network(&self) -> NetworkID {
+        self.network
+    }
+    pub fn factor_source_id(&self) -> FactorSourceID {
+        self.factor_source_id
+    }
+    pub fn unsecurified_accounts(&self) -> &IndexSet<AccountVeci> {
+        &self.unsecurified_accounts
+    }
+    pub fn unsecurified_identities(&self) -> &IndexSet<IdentityVeci> {
+        &self.unsecurified_identities
+    }
+    pub fn securified_accounts(&self) -> &IndexSet<AccountMfa> {
+        &self.securified_accounts
+    }
+    pub fn securified_identities(&self) -> &IndexSet<IdentityMfa> {
+        &self.securified_identities
+    }
+    pub fn securified_accounts_rola(&self) -> &IndexSet<AccountRola> {
+        &self.securified_accounts_rola
     }
     pub fn is_full(&self) -> bool {
         self.unsecurified_accounts.len() == CACHE_SIZE as usize
             && self.unsecurified_identities.len() == CACHE_SIZE as usize
+            && self.securified_accounts.len() == CACHE_SIZE as usize
+            && self.securified_identities.len() == CACHE_SIZE as usize
+            && self.securified_accounts_rola.len() == CACHE_SIZE as usize
     }
     pub fn new(
         network: NetworkID,
         factor_source_id: FactorSourceID,
         unsecurified_accounts: IndexSet<AccountVeci>,
         unsecurified_identities: IndexSet<IdentityVeci>,
+        securified_accounts: IndexSet<AccountMfa>,
+        securified_identities: IndexSet<IdentityMfa>,
+        securified_accounts_rola: IndexSet<AccountRola>,
     ) -> Result<Self> {
-        if !(unsecurified_accounts
-            .iter()
-            .all(|f| f.network_id() == network)
-            && unsecurified_identities
-                .iter()
-                .all(|f| f.network_id() == network))
-        {
-            return Err(CommonError::NetworkDiscrepancy);
-        }
-
-        if !(unsecurified_accounts
-            .iter()
-            .all(|f| f.network_id() == network)
-            && unsecurified_identities
-                .iter()
-                .all(|f| f.network_id() == network))
-        {
-            return Err(CommonError::FactorSourceDiscrepancy);
-        }
-
-        Ok(Self {
+        let collections = Self {
             hidden_constructor: HiddenConstructor,
             network,
             factor_source_id,
             unsecurified_accounts,
             unsecurified_identities,
-        })
+            securified_accounts,
+            securified_identities,
+            securified_accounts_rola,
+        };
+        collections.validate()?;
+        Ok(collections)
+    }
+
+    /// Every instance must be of the factor source and network of self and
+    /// match the template of its set, in which entity indices must be
+    /// strictly increasing.
+    fn validate(&self) -> Result<()> {
+        for template in DerivationTemplate::all() {
+            let mut previous: Option<CAP26EntityIndex> = None;
+            for instance in self.instances_for_template(template) {
+                let factor_source_id = instance.factor_source_id;
+                let derivation_path = instance.derivation_path;
+                if factor_source_id != self.factor_source_id {
+                    return Err(CommonError::CollectionFactorSourceDiscrepancy {
+                        factor_source_id,
+                        derivation_path,
+                    });
+                }
+                if derivation_path.network_id != self.network {
+                    return Err(CommonError::CollectionNetworkDiscrepancy {
+                        factor_source_id,
+                        derivation_path,
+                    });
+                }
+                if !template.matches(&derivation_path) {
+                    return Err(CommonError::CollectionTemplateDiscrepancy {
+                        factor_source_id,
+                        derivation_path,
+                    });
+                }
+                let index = derivation_path.entity_index.index();
+                match previous.map(|p| p.index()) {
+                    Some(p) if p == index => {
+                        return Err(CommonError::CollectionDuplicateEntityIndex {
+                            factor_source_id,
+                            derivation_path,
+                        })
+                    }
+                    Some(p) if p > index => {
+                        return Err(CommonError::CollectionNonIncreasingEntityIndex {
+                            factor_source_id,
+                            derivation_path,
+                        })
+                    }
+                    _ => {}
+                }
+                previous = Some(derivation_path.entity_index);
+            }
+        }
+        Ok(())
+    }
+
+    /// Validates each instance using the typed wrapper of `template`, and
+    /// the collections as in `new`.
+    pub fn with_instances(
+        network: NetworkID,
+        factor_source_id: FactorSourceID,
+        per_template: IndexMap<DerivationTemplate, IndexSet<HDFactorInstance>>,
+    ) -> Result<Self> {
+        let mut collections = Self::empty(network, factor_source_id);
+        for (template, instances) in per_template {
+            collections.append(template, instances)?;
+        }
+        Ok(collections)
+    }
+
+    /// The instances for `template` in the order they are to be used.
+    pub fn instances_for_template(
+        &self,
+        template: DerivationTemplate,
+    ) -> IndexSet<HDFactorInstance> {
+        fn instances(set: &IndexSet<impl IsHDFactorInstance>) -> IndexSet<HDFactorInstance> {
+            set.iter().map(|f| f.instance()).collect()
+        }
+        match template {
+            DerivationTemplate::AccountVeci => instances(&self.unsecurified_accounts),
+            DerivationTemplate::IdentityVeci => instances(&self.unsecurified_identities),
+            DerivationTemplate::AccountMfa => instances(&self.securified_accounts),
+            DerivationTemplate::IdentityMfa => instances(&self.securified_identities),
+            DerivationTemplate::AccountRola => instances(&self.securified_accounts_rola),
+        }
+    }
+
+    /// Appends `instances` to the set of `template`, validating them using
+    /// the typed wrapper of `template`, and the collections as in `new`.
+    /// Leaves self unchanged if invalid.
+    pub fn append(
+        &mut self,
+        template: DerivationTemplate,
+        instances: IndexSet<HDFactorInstance>,
+    ) -> Result<()> {
+        let mut appended = self.clone();
+        appended.extend(template, instances)?;
+        appended.validate()?;
+        *self = appended;
+        Ok(())
+    }
+
+    /// Appends all instances of `other`, which must be for the same network
+    /// and factor source, with indices after those of self. Leaves self
+    /// unchanged if invalid.
+    pub fn append_all(&mut self, other: CollectionsOfFactorInstances) -> Result<()> {
+        let mut appended = self.clone();
+        for template in DerivationTemplate::all() {
+            appended.extend(template, other.instances_for_template(template))?;
+        }
+        appended.validate()?;
+        *self = appended;
+        Ok(())
+    }
+
+    /// Appends `instances` to the set of `template` without validating the
+    /// collections, used by tests to corrupt them.
+    #[cfg(test)]
+    pub(crate) fn append_unvalidated(
+        &mut self,
+        template: DerivationTemplate,
+        instances: IndexSet<HDFactorInstance>,
+    ) -> Result<()> {
+        self.extend(template, instances)
+    }
+
+    /// Validates `instances` using the typed wrapper of `template` and adds
+    /// them to its set, failing on instances already in it.
+    fn extend(
+        &mut self,
+        template: DerivationTemplate,
+        instances: IndexSet<HDFactorInstance>,
+    ) -> Result<()> {
+        fn extend<T: std::hash::Hash + Eq>(
+            set: &mut IndexSet<T>,
+            instances: IndexSet<HDFactorInstance>,
+            ctor: impl Fn(HDFactorInstance) -> Result<T>,
+        ) -> Result<()> {
+            for instance in instances {
+                let factor_source_id = instance.factor_source_id;
+                let derivation_path = instance.derivation_path;
+                if !set.insert(ctor(instance)?) {
+                    return Err(CommonError::CollectionDuplicateEntityIndex {
+                        factor_source_id,
+                        derivation_path,
+                    });
+                }
+            }
+            Ok(())
+        }
+        match template {
+            DerivationTemplate::AccountVeci => {
+                extend(&mut self.unsecurified_accounts, instances, AccountVeci::new)
+            }
+            DerivationTemplate::IdentityVeci => extend(
+                &mut self.unsecurified_identities,
+                instances,
+                IdentityVeci::new,
+            ),
+            DerivationTemplate::AccountMfa => {
+                extend(&mut self.securified_accounts, instances, AccountMfa::new)
+            }
+            DerivationTemplate::IdentityMfa => {
+                extend(&mut self.securified_identities, instances, IdentityMfa::new)
+            }
+            DerivationTemplate::AccountRola => extend(
+                &mut self.securified_accounts_rola,
+                instances,
+                AccountRola::new,
+            ),
+        }
+    }
+
+    pub fn take_first_account_veci(&mut self) -> Option<AccountVeci> {
+        self.unsecurified_accounts.shift_remove_index(0)
+    }
+    pub fn take_first_identity_veci(&mut self) -> Option<IdentityVeci> {
+        self.unsecurified_identities.shift_remove_index(0)
+    }
+
+    /// Removes and returns the first (at most) `quantity` instances of `template`.
+    pub fn take_first(
+        &mut self,
+        template: DerivationTemplate,
+        quantity: usize,
+    ) -> IndexSet<HDFactorInstance> {
+        fn take<T: IsHDFactorInstance + std::hash::Hash + Eq>(
+            set: &mut IndexSet<T>,
+            quantity: usize,
+        ) -> IndexSet<HDFactorInstance> {
+            let quantity = quantity.min(set.len());
+            set.drain(..quantity).map(|f| f.instance()).collect()
+        }
+        match template {
+            DerivationTemplate::AccountVeci => take(&mut self.unsecurified_accounts, quantity),
+            DerivationTemplate::IdentityVeci => take(&mut self.unsecurified_identities, quantity),
+            DerivationTemplate::AccountMfa => take(&mut self.securified_accounts, quantity),
+            DerivationTemplate::IdentityMfa => take(&mut self.securified_identities, quantity),
+            DerivationTemplate::AccountRola => take(&mut self.securified_accounts_rola, quantity),
+        }
     }
 }
 
@@ -170,8 +513,40 @@ impl ToUseDirectly {
     pub fn just(factor_instance: HDFactorInstance) -> Self {
         Self::new(IndexSet::from_iter([factor_instance]))
     }
+    pub fn instances(&self) -> IndexSet<HDFactorInstance> {
+        self.0.clone()
+    }
     pub fn account_veci(self) -> Result<AccountVeci> {
-        todo!()
+        let instance = self
+            .0
+            .into_iter()
+            .exactly_one()
+            .map_err(|_| CommonError::ExpectedValue)?;
+        AccountVeci::new(instance)
+    }
+
+    pub fn identity_veci(self) -> Result<IdentityVeci> {
+        let instance = self
+            .0
+            .into_iter()
+            .exactly_one()
+            .map_err(|_| CommonError::ExpectedValue)?;
+        IdentityVeci::new(instance)
+    }
+
+    /// The instances grouped by factor source, in order, which can be used
+    /// to build a `MatrixOfFactorInstances` per account to securify.
+    pub fn account_mfa_per_factor_source(
+        self,
+    ) -> Result<IndexMap<FactorSourceID, IndexSet<AccountMfa>>> {
+        let mut per_factor_source = IndexMap::<FactorSourceID, IndexSet<AccountMfa>>::new();
+        for instance in self.0 {
+            per_factor_source
+                .entry(instance.factor_source_id)
+                .or_default()
+                .insert(AccountMfa::new(instance)?);
+        }
+        Ok(per_factor_source)
     }
 }
 
@@ -179,6 +554,288 @@ impl ToUseDirectly {
 pub struct DerivationPathPerFactorSource {
     per_factor_source: IndexMap<FactorSourceID, IndexSet<DerivationPath>>,
 }
+impl DerivationPathPerFactorSource {
+    pub fn new(per_factor_source: IndexMap<FactorSourceID, IndexSet<DerivationPath>>) -> Self {
+        Self { per_factor_source }
+    }
+    pub fn paths(&self) -> IndexMap<FactorSourceID, IndexSet<DerivationPath>> {
+        self.per_factor_source.clone()
+    }
+}
 
 #[derive(Debug, Clone, PartialEq, Eq)]
 pub struct ToCache(pub CollectionsOfFactorInstances);
+
+#[cfg(test)]
+mod tests {
+
+    use super::*;
+
+    fn instance(template: DerivationTemplate) -> HDFactorInstance {
+        HDFactorSource::sample()
+            .derive(&IndexSet::from_iter([template
+                .derivation_path(NetworkID::Mainnet, 0)
+                .unwrap()]))
+            .unwrap()
+            .into_iter()
+            .next()
+            .unwrap()
+    }
+
+    #[test]
+    fn typed_wrappers_only_accept_their_template() {
+        for template in DerivationTemplate::all() {
+            let f = instance(template);
+            assert_eq!(
+                AccountVeci::new(f.clone()).is_ok(),
+                template == DerivationTemplate::AccountVeci
+            );
+            assert_eq!(
+                IdentityVeci::new(f.clone()).is_ok(),
+                template == DerivationTemplate::IdentityVeci
+            );
+            assert_eq!(
+                AccountMfa::new(f.clone()).is_ok(),
+                template == DerivationTemplate::AccountMfa
+            );
+            assert_eq!(
+                IdentityMfa::new(f.clone()).is_ok(),
+                template == DerivationTemplate::IdentityMfa
+            );
+            assert_eq!(
+                AccountRola::new(f).is_ok(),
+                template == DerivationTemplate::AccountRola
+            );
+        }
+    }
+
+    #[test]
+    fn typed_wrappers_reject_index_outside_key_space() {
+        let mut f = instance(DerivationTemplate::AccountVeci);
+        f.derivation_path.entity_index = CAP26EntityIndex::Unsecurified(KEY_SPACE_SIZE);
+        assert_eq!(
+            AccountVeci::new(f.clone()),
+            Err(CommonError::CAP26EntityIndexOverflow)
+        );
+        assert_eq!(
+            HDFactorInstance::new(f.derivation_path, f.factor_source_id, f.public_key),
+            Err(CommonError::CAP26EntityIndexOverflow)
+        );
+    }
+
+    #[test]
+    fn each_template_has_its_own_set() {
+        let mut sut =
+            CollectionsOfFactorInstances::empty(NetworkID::Mainnet, FactorSourceID::sample());
+        for template in DerivationTemplate::all() {
+            sut.append(template, IndexSet::from_iter([instance(template)]))
+                .unwrap();
+        }
+        for template in DerivationTemplate::all() {
+            assert_eq!(
+                sut.instances_for_template(template),
+                IndexSet::<HDFactorInstance>::from_iter([instance(template)])
+            );
+        }
+        assert_eq!(
+            sut.append(
+                DerivationTemplate::AccountRola,
+                IndexSet::from_iter([instance(DerivationTemplate::AccountMfa)])
+            ),
+            Err(CommonError::KeyKindDiscrepancy {
+                factor_source_id: FactorSourceID::sample(),
+                derivation_path: DerivationTemplate::AccountMfa
+                    .derivation_path(NetworkID::Mainnet, 0)
+                    .unwrap(),
+                expected: CAP26KeyKind::AuthenticationSigning,
+                found: CAP26KeyKind::TransactionSigning,
+            })
+        );
+    }
+
+    fn account_vecis(
+        factor_source: &HDFactorSource,
+        network_id: NetworkID,
+        indices: impl IntoIterator<Item = u32>,
+    ) -> IndexSet<AccountVeci> {
+        indices
+            .into_iter()
+            .map(|i| {
+                let path = DerivationTemplate::AccountVeci
+                    .derivation_path(network_id, i)
+                    .unwrap();
+                factor_source
+                    .derive(&IndexSet::from_iter([path]))
+                    .unwrap()
+                    .into_iter()
+                    .map(|f| AccountVeci::new(f).unwrap())
+                    .next()
+                    .unwrap()
+            })
+            .collect()
+    }
+
+    fn collections_with(
+        unsecurified_accounts: IndexSet<AccountVeci>,
+    ) -> Result<CollectionsOfFactorInstances> {
+        CollectionsOfFactorInstances::new(
+            NetworkID::Mainnet,
+            FactorSourceID::sample(),
+            unsecurified_accounts,
+            IndexSet::new(),
+            IndexSet::new(),
+            IndexSet::new(),
+            IndexSet::new(),
+        )
+    }
+
+    #[test]
+    fn collections_accept_increasing_indices() {
+        assert!(collections_with(account_vecis(
+            &HDFactorSource::sample(),
+            NetworkID::Mainnet,
+            [0, 1, 5]
+        ))
+        .is_ok());
+    }
+
+    #[test]
+    fn collections_reject_instance_of_other_factor_source() {
+        let other = HDFactorSource::sample_other();
+        assert_eq!(
+            collections_with(account_vecis(&other, NetworkID::Mainnet, [0])).err(),
+            Some(CommonError::CollectionFactorSourceDiscrepancy {
+                factor_source_id: other.factor_source_id,
+                derivation_path: DerivationTemplate::AccountVeci
+                    .derivation_path(NetworkID::Mainnet, 0)
+                    .unwrap(),
+            })
+        );
+    }
+
+    #[test]
+    fn collections_reject_instance_of_other_network() {
+        assert_eq!(
+            collections_with(account_vecis(
+                &HDFactorSource::sample(),
+                NetworkID::Testnet,
+                [0]
+            ))
+            .err(),
+            Some(CommonError::CollectionNetworkDiscrepancy {
+                factor_source_id: FactorSourceID::sample(),
+                derivation_path: DerivationTemplate::AccountVeci
+                    .derivation_path(NetworkID::Testnet, 0)
+                    .unwrap(),
+            })
+        );
+    }
+
+    #[test]
+    fn collections_reject_non_increasing_indices() {
+        assert_eq!(
+            collections_with(account_vecis(
+                &HDFactorSource::sample(),
+                NetworkID::Mainnet,
+                [0, 2, 1]
+            ))
+            .err(),
+            Some(CommonError::CollectionNonIncreasingEntityIndex {
+                factor_source_id: FactorSourceID::sample(),
+                derivation_path: DerivationTemplate::AccountVeci
+                    .derivation_path(NetworkID::Mainnet, 1)
+                    .unwrap(),
+            })
+        );
+    }
+
+    #[test]
+    fn collections_reject_duplicate_indices() {
+        let path = DerivationTemplate::AccountVeci
+            .derivation_path(NetworkID::Mainnet, 0)
+            .unwrap();
+        let other_key = HDFactorSource::sample_other()
+            .derive(&IndexSet::from_iter([path]))
+            .unwrap()
+            .into_iter()
+            .next()
+            .unwrap()
+            .public_key;
+        let mut unsecurified_accounts =
+            account_vecis(&HDFactorSource::sample(), NetworkID::Mainnet, [0]);
+        unsecurified_accounts.insert(
+            AccountVeci::new(
+                HDFactorInstance::new(path, FactorSourceID::sample(), other_key).unwrap(),
+            )
+            .unwrap(),
+        );
+        assert_eq!(
+            collections_with(unsecurified_accounts).err(),
+            Some(CommonError::CollectionDuplicateEntityIndex {
+                factor_source_id: FactorSourceID::sample(),
+                derivation_path: path,
+            })
+        );
+    }
+
+    #[test]
+    fn append_rejects_invalid_instances_leaving_collections_unchanged() {
+        let bdfs = HDFactorSource::sample();
+        let instances = |factor_source: &HDFactorSource, network_id, indices: [u32; 1]| {
+            account_vecis(factor_source, network_id, indices)
+                .into_iter()
+                .map(|f| f.instance())
+                .collect::<IndexSet<_>>()
+        };
+        let mut sut = collections_with(account_vecis(&bdfs, NetworkID::Mainnet, [0, 2])).unwrap();
+        let before = sut.clone();
+        let path = |network_id, index| {
+            DerivationTemplate::AccountVeci
+                .derivation_path(network_id, index)
+                .unwrap()
+        };
+
+        for (appended, error) in [
+            (
+                instances(&HDFactorSource::sample_other(), NetworkID::Mainnet, [3]),
+                CommonError::CollectionFactorSourceDiscrepancy {
+                    factor_source_id: HDFactorSource::sample_other().factor_source_id,
+                    derivation_path: path(NetworkID::Mainnet, 3),
+                },
+            ),
+            (
+                instances(&bdfs, NetworkID::Testnet, [3]),
+                CommonError::CollectionNetworkDiscrepancy {
+                    factor_source_id: bdfs.factor_source_id,
+                    derivation_path: path(NetworkID::Testnet, 3),
+                },
+            ),
+            (
+                instances(&bdfs, NetworkID::Mainnet, [2]),
+                CommonError::CollectionDuplicateEntityIndex {
+                    factor_source_id: bdfs.factor_source_id,
+                    derivation_path: path(NetworkID::Mainnet, 2),
+                },
+            ),
+            (
+                instances(&bdfs, NetworkID::Mainnet, [1]),
+                CommonError::CollectionNonIncreasingEntityIndex {
+                    factor_source_id: bdfs.factor_source_id,
+                    derivation_path: path(NetworkID::Mainnet, 1),
+                },
+            ),
+        ] {
+            assert_eq!(
+                sut.append(DerivationTemplate::AccountVeci, appended.clone()),
+                Err(error.clone())
+            );
+            let mut other =
+                CollectionsOfFactorInstances::empty(NetworkID::Mainnet, bdfs.factor_source_id);
+            other
+                .extend(DerivationTemplate::AccountVeci, appended)
+                .unwrap();
+            assert_eq!(sut.append_all(other), Err(error));
+            assert_eq!(sut, before);
+        }
+    }
+}
@@ -1,7 +1,17 @@
 mod cache;
+mod cache_integrity;
+mod cache_serialization;
+mod eviction;
+mod journal;
+mod lease;
 mod mixed;
 mod next_derivation_entity_index_assigner;
 
 pub use cache::*;
+pub use cache_integrity::*;
+pub use cache_serialization::*;
+pub use eviction::*;
+pub use journal::*;
+pub use lease::*;
 pub use mixed::*;
 pub use next_derivation_entity_index_assigner::*;
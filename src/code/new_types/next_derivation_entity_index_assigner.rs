@@ -1,3 +1,5 @@
+use std::sync::RwLock;
+
 use crate::prelude::*;
 
 pub struct NextDerivationEntityIndexProfileAnalyzingAssigner {
@@ -21,38 +23,464 @@ impl NextDerivationEntityIndexProfileAnalyzingAssigner {
                 .unwrap_or_default(),
         }
     }
+
+    /// Every factor instance of every account and persona on the network,
+    /// both unsecurified ones and all factors of securified ones.
+    fn all_factor_instances(&self) -> IndexSet<HDFactorInstance> {
+        self.accounts_on_network
+            .iter()
+            .map(|a| a.security_state())
+            .chain(self.personas_on_network.iter().map(|p| p.security_state()))
+            .flat_map(|s| s.all_factor_instances())
+            .filter(|f| f.derivation_path.network_id == self.network_id)
+            .collect()
+    }
+
+    /// The highest index used in the Profile by `factor_source_id`, for
+    /// `entity_kind`, `key_kind` and `key_space`, if any.
+    pub fn highest_used_index(
+        &self,
+        factor_source_id: FactorSourceID,
+        entity_kind: CAP26EntityKind,
+        key_kind: CAP26KeyKind,
+        key_space: KeySpace,
+    ) -> Option<CAP26EntityIndex> {
+        self.all_factor_instances()
+            .into_iter()
+            .filter(|f| f.factor_source_id == factor_source_id)
+            .map(|f| f.derivation_path)
+            .filter(|p| {
+                p.entity_kind == entity_kind && p.key_kind == key_kind && p.key_space() == key_space
+            })
+            .map(|p| p.entity_index)
+            .max_by_key(|i| i.index())
+    }
+
+    /// The index right after the highest index used in the Profile for
+    /// `template` by `factor_source_id`, or the first index of the key space
+    /// of `template` if none is used. Fails if the key space is exhausted.
+    pub fn next(
+        &self,
+        factor_source_id: FactorSourceID,
+        template: DerivationTemplate,
+    ) -> Result<CAP26EntityIndex> {
+        self.highest_used_index(
+            factor_source_id,
+            template.entity_kind(),
+            template.key_kind(),
+            template.key_space(),
+        )
+        .map(|i| i.next())
+        .unwrap_or_else(|| Ok(CAP26EntityIndex::new(template.key_space(), 0)))
+    }
 }
 
-#[derive(Debug)]
-pub struct NextDerivationEntityIndexWithLocalOffsets {
-    network_id: NetworkID,
-    local_offsets: HashMap<FactorSourceID, u32>,
+/// Analyzes a snapshot of the cache - and the instances outstanding, i.e.
+/// leased or pending in its journal - so that instances derived to refill
+/// the cache never collide with - or leave a gap after - cached ones, nor
+/// collide with ones taken out of the cache but not yet in Profile.
+pub struct NextDerivationEntityIndexCacheAnalyzingAssigner {
+    cache: FactorInstancesForSpecificNetworkCache,
+    outstanding: IndexSet<HDFactorInstance>,
 }
-impl NextDerivationEntityIndexWithLocalOffsets {
-    pub fn empty(network_id: NetworkID) -> Self {
+impl NextDerivationEntityIndexCacheAnalyzingAssigner {
+    pub fn new(
+        cache: &FactorInstancesForSpecificNetworkCache,
+        outstanding: IndexSet<HDFactorInstance>,
+    ) -> Self {
         Self {
-            network_id,
-            local_offsets: HashMap::new(),
+            cache: cache.cloned_snapshot(),
+            outstanding,
         }
     }
+
+    /// The index right after the last cached or outstanding instance for
+    /// `template` of `factor_source_id`, if any. Fails if the key space is
+    /// exhausted.
+    pub fn next(
+        &self,
+        factor_source_id: FactorSourceID,
+        template: DerivationTemplate,
+    ) -> Result<Option<CAP26EntityIndex>> {
+        self.cache
+            .peek_all_instances_for_factor_source(factor_source_id)
+            .map(|c| c.instances_for_template(template))
+            .unwrap_or_default()
+            .iter()
+            .chain(self.outstanding.iter().filter(|f| {
+                f.factor_source_id == factor_source_id && template.matches(&f.derivation_path)
+            }))
+            .map(|f| f.derivation_path.entity_index)
+            .max_by_key(|i| i.index())
+            .map(|i| i.next())
+            .transpose()
+    }
+}
+
+/// Keeps track of indices handed out - but not yet used in Profile nor
+/// put in cache - so that many indices can be assigned during a single
+/// provider call without overlapping.
+#[derive(Debug, Default)]
+pub struct NextDerivationEntityIndexWithLocalOffsets {
+    /// Number of indices reserved so far, per factor source and template,
+    /// relative to the base index they were reserved from.
+    local_offsets: RwLock<HashMap<FactorSourceID, HashMap<DerivationTemplate, u32>>>,
+}
+impl NextDerivationEntityIndexWithLocalOffsets {
+    /// The number of indices reserved for `template` using `factor_source_id`.
+    pub fn offset(&self, factor_source_id: FactorSourceID, template: DerivationTemplate) -> u32 {
+        self.local_offsets
+            .read()
+            .unwrap()
+            .get(&factor_source_id)
+            .and_then(|per_template| per_template.get(&template))
+            .copied()
+            .unwrap_or_default()
+    }
+
+    /// Reserves `quantity` consecutive indices following any previously
+    /// reserved ones, starting at `base`. Fails - without reserving anything -
+    /// if they do not fit in the key space of `base`.
+    pub fn reserve(
+        &self,
+        factor_source_id: FactorSourceID,
+        template: DerivationTemplate,
+        base: CAP26EntityIndex,
+        quantity: u32,
+    ) -> Result<IndexSet<CAP26EntityIndex>> {
+        let mut binding = self.local_offsets.write().unwrap();
+        let offset = binding
+            .entry(factor_source_id)
+            .or_default()
+            .entry(template)
+            .or_default();
+        let reserved = base.checked_add(*offset)?.range(quantity)?;
+        *offset += quantity;
+        Ok(reserved)
+    }
 }
 
 pub struct NextDerivationEntityIndexAssigner {
-    network_id: NetworkID,
     profile_analyzing: NextDerivationEntityIndexProfileAnalyzingAssigner,
+    cache_analyzing: NextDerivationEntityIndexCacheAnalyzingAssigner,
     local_offsets: NextDerivationEntityIndexWithLocalOffsets,
 }
 impl NextDerivationEntityIndexAssigner {
-    pub fn new(network_id: NetworkID, profile: Option<Profile>) -> Self {
+    /// `cache` is analyzed as it is now, so later consumption from it does not
+    /// change the assigned indices, together with the `outstanding` instances
+    /// taken out of it, i.e. leased or pending in its journal. Fails if `cache`
+    /// is not on `network_id`.
+    pub fn new(
+        network_id: NetworkID,
+        profile: Option<Profile>,
+        cache: &FactorInstancesForSpecificNetworkCache,
+        outstanding: IndexSet<HDFactorInstance>,
+    ) -> Result<Self> {
+        if cache.network_id != network_id {
+            return Err(CommonError::NetworkDiscrepancy {
+                expected: network_id,
+                found: cache.network_id,
+            });
+        }
         let profile_analyzing =
             NextDerivationEntityIndexProfileAnalyzingAssigner::new(network_id, profile);
-        Self {
-            network_id,
+        Ok(Self {
             profile_analyzing,
-            local_offsets: NextDerivationEntityIndexWithLocalOffsets::empty(network_id),
-        }
+            cache_analyzing: NextDerivationEntityIndexCacheAnalyzingAssigner::new(
+                cache,
+                outstanding,
+            ),
+            local_offsets: NextDerivationEntityIndexWithLocalOffsets::default(),
+        })
+    }
+    /// The first index not used in the Profile, in the cache nor outstanding,
+    /// for `template` using `factor_source_id`, ignoring reservations.
+    fn base(
+        &self,
+        factor_source_id: FactorSourceID,
+        template: DerivationTemplate,
+    ) -> Result<CAP26EntityIndex> {
+        let profile = self.profile_analyzing.next(factor_source_id, template)?;
+        Ok(self
+            .cache_analyzing
+            .next(factor_source_id, template)?
+            .into_iter()
+            .chain([profile])
+            .max_by_key(|i| i.index())
+            .unwrap_or(profile))
+    }
+
+    /// The next free index for `template` using `factor_source_id`, never an
+    /// index already used in the Profile, in the cache nor already reserved.
+    pub fn next(
+        &self,
+        factor_source_id: FactorSourceID,
+        template: DerivationTemplate,
+    ) -> Result<CAP26EntityIndex> {
+        let base = self.base(factor_source_id, template)?;
+        base.checked_add(self.local_offsets.offset(factor_source_id, template))
+    }
+
+    /// Reserves `quantity` consecutive free indices for `template` using
+    /// `factor_source_id`, subsequent calls never return the same indices.
+    pub fn reserve(
+        &self,
+        factor_source_id: FactorSourceID,
+        template: DerivationTemplate,
+        quantity: u32,
+    ) -> Result<IndexSet<CAP26EntityIndex>> {
+        let base = self.base(factor_source_id, template)?;
+        self.local_offsets
+            .reserve(factor_source_id, template, base, quantity)
+    }
+    pub fn next_account_veci(&self, factor_source_id: FactorSourceID) -> Result<CAP26EntityIndex> {
+        self.next(factor_source_id, DerivationTemplate::AccountVeci)
+    }
+    pub fn next_identity_veci(&self, factor_source_id: FactorSourceID) -> Result<CAP26EntityIndex> {
+        self.next(factor_source_id, DerivationTemplate::IdentityVeci)
+    }
+    pub fn next_account_mfa(&self, factor_source_id: FactorSourceID) -> Result<CAP26EntityIndex> {
+        self.next(factor_source_id, DerivationTemplate::AccountMfa)
+    }
+    pub fn next_identity_mfa(&self, factor_source_id: FactorSourceID) -> Result<CAP26EntityIndex> {
+        self.next(factor_source_id, DerivationTemplate::IdentityMfa)
+    }
+    pub fn next_account_rola(&self, factor_source_id: FactorSourceID) -> Result<CAP26EntityIndex> {
+        self.next(factor_source_id, DerivationTemplate::AccountRola)
     }
-    pub fn next_account_veci(&self, factor_source_id: FactorSourceID) -> CAP26EntityIndex {
-        todo!()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    type Sut = NextDerivationEntityIndexAssigner;
+
+    fn instance(template: DerivationTemplate, index: u32) -> HDFactorInstance {
+        let path = template.derivation_path(NetworkID::Mainnet, index).unwrap();
+        HDFactorSource::sample()
+            .derive(&IndexSet::from_iter([path]))
+            .unwrap()
+            .pop()
+            .unwrap()
+    }
+
+    #[test]
+    fn next_is_after_highest_used_in_profile_per_template() {
+        let accounts = [
+            Account::new(EntitySecurityState::Unsecurified(instance(
+                DerivationTemplate::AccountVeci,
+                4,
+            ))),
+            Account::new(EntitySecurityState::Securified(
+                MatrixOfFactorInstances::new(
+                    1,
+                    IndexSet::from_iter([instance(DerivationTemplate::AccountMfa, 7)]),
+                    IndexSet::from_iter([instance(DerivationTemplate::AccountMfa, 2)]),
+                ),
+            )),
+        ];
+        let personas = [Persona::new(EntitySecurityState::Unsecurified(instance(
+            DerivationTemplate::IdentityVeci,
+            1,
+        )))];
+        let profile = Profile {
+            factor_sources: IndexSet::new(),
+            networks: IndexMap::from_iter([(
+                NetworkID::Mainnet,
+                ProfileOnNetwork {
+                    network_id: NetworkID::Mainnet,
+                    accounts: IndexSet::from_iter(accounts),
+                    personas: IndexSet::from_iter(personas),
+                },
+            )]),
+        };
+        let id = FactorSourceID::sample();
+
+        let sut = Sut::new(
+            NetworkID::Mainnet,
+            Some(profile.clone()),
+            &FactorInstancesForSpecificNetworkCache::empty(NetworkID::Mainnet),
+            IndexSet::new(),
+        )
+        .unwrap();
+
+        assert_eq!(
+            sut.next_account_veci(id).unwrap(),
+            CAP26EntityIndex::Unsecurified(5)
+        );
+        assert_eq!(
+            sut.next_account_mfa(id).unwrap(),
+            CAP26EntityIndex::Securified(8)
+        );
+        assert_eq!(
+            sut.next_identity_veci(id).unwrap(),
+            CAP26EntityIndex::Unsecurified(2)
+        );
+        assert_eq!(
+            sut.next_identity_mfa(id).unwrap(),
+            CAP26EntityIndex::Securified(0)
+        );
+        assert_eq!(
+            sut.next_account_rola(id).unwrap(),
+            CAP26EntityIndex::Securified(0)
+        );
+        assert_eq!(
+            sut.next_account_veci(FactorSourceID::sample_other())
+                .unwrap(),
+            CAP26EntityIndex::Unsecurified(0)
+        );
+        assert_eq!(
+            Sut::new(
+                NetworkID::Testnet,
+                Some(profile),
+                &FactorInstancesForSpecificNetworkCache::empty(NetworkID::Testnet),
+                IndexSet::new(),
+            )
+            .unwrap()
+            .next_account_veci(id)
+            .unwrap(),
+            CAP26EntityIndex::Unsecurified(0)
+        );
+    }
+
+    #[test]
+    fn reserve_hands_out_consecutive_indices_per_factor_source_and_template() {
+        let sut = Sut::new(
+            NetworkID::Mainnet,
+            None,
+            &FactorInstancesForSpecificNetworkCache::empty(NetworkID::Mainnet),
+            IndexSet::new(),
+        )
+        .unwrap();
+        let id = FactorSourceID::sample();
+        let other = FactorSourceID::sample_other();
+
+        let first = sut.reserve(id, DerivationTemplate::AccountMfa, 3).unwrap();
+        let second = sut.reserve(id, DerivationTemplate::AccountMfa, 2).unwrap();
+
+        assert_eq!(
+            first.into_iter().chain(second).collect_vec(),
+            (0..5).map(CAP26EntityIndex::Securified).collect_vec()
+        );
+        assert_eq!(
+            sut.next_account_mfa(id).unwrap(),
+            CAP26EntityIndex::Securified(5)
+        );
+        assert_eq!(
+            sut.next_identity_mfa(id).unwrap(),
+            CAP26EntityIndex::Securified(0)
+        );
+        assert_eq!(
+            sut.reserve(other, DerivationTemplate::AccountMfa, 1)
+                .unwrap(),
+            IndexSet::<CAP26EntityIndex>::from_iter([CAP26EntityIndex::Securified(0)])
+        );
+    }
+
+    #[test]
+    fn next_is_after_max_of_profile_and_cache() {
+        let network = NetworkID::Mainnet;
+        let id = FactorSourceID::sample();
+        let profile = Profile {
+            factor_sources: IndexSet::new(),
+            networks: IndexMap::from_iter([(
+                network,
+                ProfileOnNetwork {
+                    network_id: network,
+                    accounts: IndexSet::from_iter([Account::new(
+                        EntitySecurityState::Unsecurified(instance(
+                            DerivationTemplate::AccountVeci,
+                            9,
+                        )),
+                    )]),
+                    personas: IndexSet::new(),
+                },
+            )]),
+        };
+        let cache = FactorInstancesForSpecificNetworkCache::empty(network);
+        cache
+            .append_for_factor(
+                id,
+                ToCache(
+                    CollectionsOfFactorInstances::with_instances(
+                        network,
+                        id,
+                        IndexMap::from_iter([
+                            (
+                                DerivationTemplate::AccountVeci,
+                                IndexSet::from_iter([instance(DerivationTemplate::AccountVeci, 3)]),
+                            ),
+                            (
+                                DerivationTemplate::AccountMfa,
+                                IndexSet::from_iter([instance(DerivationTemplate::AccountMfa, 4)]),
+                            ),
+                        ]),
+                    )
+                    .unwrap(),
+                ),
+            )
+            .unwrap();
+
+        let sut = Sut::new(network, Some(profile), &cache, IndexSet::new()).unwrap();
+        // Consuming from the cache after creation does not affect the result.
+        cache.consume(id, DerivationTemplate::AccountMfa, 1);
+
+        assert_eq!(
+            sut.next_account_veci(id).unwrap(),
+            CAP26EntityIndex::Unsecurified(10)
+        );
+        assert_eq!(
+            sut.next_account_mfa(id).unwrap(),
+            CAP26EntityIndex::Securified(5)
+        );
+        assert_eq!(
+            sut.reserve(id, DerivationTemplate::AccountMfa, 2).unwrap(),
+            IndexSet::<CAP26EntityIndex>::from_iter([
+                CAP26EntityIndex::Securified(5),
+                CAP26EntityIndex::Securified(6)
+            ])
+        );
+    }
+
+    #[test]
+    fn next_is_after_outstanding_instances() {
+        let id = FactorSourceID::sample();
+        let sut = Sut::new(
+            NetworkID::Mainnet,
+            None,
+            &FactorInstancesForSpecificNetworkCache::empty(NetworkID::Mainnet),
+            IndexSet::from_iter([
+                instance(DerivationTemplate::AccountVeci, 3),
+                instance(DerivationTemplate::AccountVeci, 1),
+            ]),
+        )
+        .unwrap();
+
+        assert_eq!(
+            sut.next_account_veci(id).unwrap(),
+            CAP26EntityIndex::Unsecurified(4)
+        );
+        assert_eq!(
+            sut.next_identity_veci(id).unwrap(),
+            CAP26EntityIndex::Unsecurified(0)
+        );
+    }
+
+    #[test]
+    fn cache_on_other_network_is_err() {
+        assert_eq!(
+            Sut::new(
+                NetworkID::Mainnet,
+                None,
+                &FactorInstancesForSpecificNetworkCache::empty(NetworkID::Testnet),
+                IndexSet::new(),
+            )
+            .err(),
+            Some(CommonError::NetworkDiscrepancy {
+                expected: NetworkID::Mainnet,
+                found: NetworkID::Testnet,
+            })
+        );
     }
 }
@@ -1,4 +1,7 @@
-use std::sync::{Arc, RwLock};
+use std::{
+    sync::{Arc, RwLock},
+    time::{Duration, SystemTime},
+};
 
 use crate::prelude::*;
 
@@ -11,39 +14,177 @@ pub struct FactorInstancesProvider {
     query: InstancesQuery,
 
     next_entity_index_assigner: NextDerivationEntityIndexAssigner,
+
+    interactors: KeysDerivationInteractors,
 }
 
 impl FactorInstancesProvider {
     /// `Profile` is optional since None in case of Onboarding Account Recovery Scan
     /// No need to pass Profile as mut, since we just need to read it for the
     /// next derivation entity indices.
+    ///
+    /// `outstanding` are the instances leased or pending on the network of
+    /// `cache_on_network`, never derived again.
     fn new(
         cache_on_network: FactorInstancesForSpecificNetworkCache,
+        outstanding: IndexSet<HDFactorInstance>,
         profile: impl Into<Option<Profile>>,
         query: InstancesQuery,
-    ) -> Self {
+        interactors: KeysDerivationInteractors,
+    ) -> Result<Self> {
         let network_id = cache_on_network.network_id;
-        Self {
+        let next_entity_index_assigner = NextDerivationEntityIndexAssigner::new(
+            network_id,
+            profile.into(),
+            &cache_on_network,
+            outstanding,
+        )?;
+        Ok(Self {
             cache: RwLock::new(cache_on_network),
             query,
-            next_entity_index_assigner: NextDerivationEntityIndexAssigner::new(
-                network_id,
-                profile.into(),
-            ),
-        }
+            next_entity_index_assigner,
+            interactors,
+        })
     }
 
+    /// Loads the cache from `storage` and saves it back updated with the
+    /// instances consumed from it and the newly derived ones.
     pub async fn provide(
-        cache: Arc<RwLock<FactorInstancesForEachNetworkCache>>,
+        storage: Arc<impl FactorInstancesCacheStorage>,
         network_id: NetworkID,
         profile: impl Into<Option<Profile>>,
         query: InstancesQuery,
+        interactors: KeysDerivationInteractors,
     ) -> Result<ToUseDirectly> {
-        let cloned_cache = cache.read().unwrap().clone_for_network_or_empty(network_id);
-        let provider = Self::new(cloned_cache, profile, query);
-        let provided = provider._provide().await?;
-        cache.write().unwrap().merge(provided.cache_to_persist)?;
-        Ok(provided.instances_to_be_used)
+        Self::provide_and_persist(storage, network_id, profile, query, interactors, None)
+            .await
+            .map(|(instances, _)| instances)
+    }
+
+    /// Like `provide`, but the provided instances are leased until
+    /// `lease_duration` from now: the caller MUST commit the lease once the
+    /// instances are used in Profile, or release it to put them back in the
+    /// cache, else they are put back once the lease has expired.
+    pub async fn provide_leased(
+        storage: Arc<impl FactorInstancesCacheStorage>,
+        network_id: NetworkID,
+        profile: impl Into<Option<Profile>>,
+        query: InstancesQuery,
+        interactors: KeysDerivationInteractors,
+        lease_duration: Duration,
+    ) -> Result<FactorInstancesLease> {
+        let expires_at = SystemTime::now()
+            .checked_add(lease_duration)
+            .ok_or(CommonError::LeaseDurationOverflow)?;
+        Self::provide_and_persist(
+            storage,
+            network_id,
+            profile,
+            query,
+            interactors,
+            Some(expires_at),
+        )
+        .await?
+        .1
+        .ok_or(CommonError::UnknownLease)
+    }
+
+    /// Reconciles the journal of the cache in `storage` with `profile`, which
+    /// MUST be the saved Profile: instances provided but not used in it - e.g.
+    /// because the host crashed before saving it - are put back in the cache.
+    ///
+    /// MUST be called at startup, before any instances are provided.
+    pub async fn recover(
+        storage: &impl FactorInstancesCacheStorage,
+        profile: Profile,
+    ) -> Result<JournalReconciliation> {
+        storage
+            .update(Box::new(move |cache| {
+                cache.reconcile_with_profile(&profile)
+            }))
+            .await
+    }
+
+    /// Checks the integrity of the cache in `storage` against `profile` and
+    /// repairs it: bad instances are dropped, missing indices are only
+    /// reported, since they might be used on ledger. Returns the report of
+    /// the issues found, before repairing.
+    pub async fn repair_cache(
+        storage: &impl FactorInstancesCacheStorage,
+        profile: Profile,
+    ) -> Result<CacheIntegrityReport> {
+        storage
+            .update(Box::new(move |cache| cache.repair(&profile)))
+            .await
+    }
+
+    /// Reclaims expired leases - only if `profile` is given, else the used
+    /// instances cannot be told apart - provides the instances and - in the
+    /// same atomic update of the cache - leases them until `lease_until`, if
+    /// any, else journals them as pending until reconciled with Profile.
+    async fn provide_and_persist(
+        storage: Arc<impl FactorInstancesCacheStorage>,
+        network_id: NetworkID,
+        profile: impl Into<Option<Profile>>,
+        query: InstancesQuery,
+        interactors: KeysDerivationInteractors,
+        lease_until: Option<SystemTime>,
+    ) -> Result<(ToUseDirectly, Option<FactorInstancesLease>)> {
+        let profile = profile.into();
+        if let Some(in_profile) = profile.clone() {
+            storage
+                .update(Box::new(move |cache| {
+                    cache
+                        .reclaim_expired_leases(SystemTime::now(), &in_profile)
+                        .map(|_| ())
+                }))
+                .await?;
+        }
+
+        let (to_use_directly, cache_to_persist) =
+            if let InstancesQuery::PreDeriveKeysForFactorSource { factor_source } = query {
+                let to_use_directly = Self::pre_derive_keys_for_factor_source(
+                    storage.clone(),
+                    profile,
+                    factor_source,
+                    interactors,
+                )
+                .await?;
+                (to_use_directly, None)
+            } else {
+                let cache = storage.load().await?;
+                let provider = Self::new(
+                    cache.clone_for_network_or_empty(network_id),
+                    cache.outstanding_instances(network_id),
+                    profile,
+                    query,
+                    interactors,
+                )?;
+                let provided = provider._provide().await?;
+                (
+                    provided.instances_to_be_used,
+                    Some(provided.cache_to_persist),
+                )
+            };
+
+        if cache_to_persist.is_none() && lease_until.is_none() {
+            return Ok((to_use_directly, None));
+        }
+        let instances = to_use_directly.instances();
+        let lease = storage
+            .update(Box::new(move |cache| {
+                if let Some(on_network) = cache_to_persist {
+                    cache.merge(on_network)?;
+                }
+                if let Some(expires_at) = lease_until {
+                    Ok(Some(cache.lease(network_id, instances, expires_at)))
+                } else {
+                    cache.journal_consumption(network_id, instances);
+                    Ok(None)
+                }
+            }))
+            .await?;
+        Ok((to_use_directly, lease))
     }
 
     async fn _provide(self) -> Result<ProvidedInstances> {
@@ -58,108 +199,412 @@ impl FactorInstancesProvider {
             InstancesQuery::AccountVeci { factor_source } => {
                 self.provide_account_veci(factor_source).await
             }
+            InstancesQuery::IdentityVeci { factor_source } => {
+                self.provide_identity_veci(factor_source).await
+            }
+            InstancesQuery::AccountRecoveryScan {
+                factor_source,
+                indices,
+                on_ledger_key_usage,
+            } => {
+                self.provide_account_recovery_scan(factor_source, indices, on_ledger_key_usage)
+                    .await
+            }
+            InstancesQuery::PreDeriveKeysForFactorSource { .. } => {
+                unreachable!("Spans all networks, handled by `provide`.")
+            }
         }
     }
+
+    /// Fills the cache of `factor_source` for every `DerivationTemplate` on every
+    /// network, using a single derivation - i.e. a single user interaction.
+    async fn pre_derive_keys_for_factor_source(
+        storage: Arc<impl FactorInstancesCacheStorage>,
+        profile: impl Into<Option<Profile>>,
+        factor_source: HDFactorSource,
+        interactors: KeysDerivationInteractors,
+    ) -> Result<ToUseDirectly> {
+        let factor_source_id = factor_source.factor_source_id;
+        let query = InstancesQuery::PreDeriveKeysForFactorSource { factor_source };
+        let profile = profile.into();
+        let cache = storage.load().await?;
+        let providers = NetworkID::all()
+            .into_iter()
+            .map(|network_id| {
+                Self::new(
+                    cache.clone_for_network_or_empty(network_id),
+                    cache.outstanding_instances(network_id),
+                    profile.clone(),
+                    query.clone(),
+                    interactors.clone(),
+                )
+            })
+            .collect::<Result<Vec<_>>>()?;
+
+        let paths = providers
+            .iter()
+            .map(|provider| {
+                let existing = provider
+                    .cache
+                    .read()
+                    .unwrap()
+                    .peek_all_instances_for_factor_source(factor_source_id);
+                let fill_cache = FillCacheQuantitiesForFactor::fill(factor_source_id)
+                    .subtracting_existing(existing)?;
+                provider.paths_single_factor(factor_source_id, &IndexMap::new(), fill_cache)
+            })
+            .collect::<Result<Vec<_>>>()?
+            .into_iter()
+            .flatten()
+            .collect::<IndexSet<_>>();
+
+        if paths.is_empty() {
+            return Ok(ToUseDirectly::default());
+        }
+
+        let derived = providers[0]
+            .derive(
+                DerivationPathPerFactorSource::new(IndexMap::from_iter([(
+                    factor_source_id,
+                    paths,
+                )])),
+                IndexSet::from_iter([factor_source_id]),
+            )
+            .await?
+            .instances_for_factor_source(factor_source_id);
+
+        let mut caches_to_persist = Vec::new();
+        for provider in providers {
+            let (_, to_cache) =
+                provider.split(factor_source_id, &IndexMap::new(), derived.clone())?;
+            provider
+                .cache
+                .write()
+                .unwrap()
+                .append_for_factor(factor_source_id, to_cache)?;
+            let provided = ProvidedInstances::new(
+                provider.cache.into_inner().unwrap(),
+                ToUseDirectly::default(),
+            );
+            caches_to_persist.push(provided.cache_to_persist);
+        }
+        storage
+            .update(Box::new(move |cache| {
+                caches_to_persist
+                    .into_iter()
+                    .try_for_each(|on_network| cache.merge(on_network))
+            }))
+            .await?;
+        Ok(ToUseDirectly::default())
+    }
 }
 
 impl FactorInstancesProvider {
+    /// The paths to derive for `factor_source_id`, for each template the
+    /// quantity to use directly plus the quantity to fill the cache with,
+    /// at indices reserved from the next free index of that template - i.e.
+    /// after those used in the Profile or cached. Fails if a key space is
+    /// exhausted.
     fn paths_single_factor(
         &self,
         factor_source_id: FactorSourceID,
-        known_indices_for_templates: IndexMap<DerivationTemplate, CAP26EntityIndex>,
+        to_use_directly: &IndexMap<DerivationTemplate, usize>,
         fill_cache: FillCacheQuantitiesForFactor,
-    ) -> DerivationPathPerFactorSource {
-        todo!()
+    ) -> Result<IndexSet<DerivationPath>> {
+        let network_id = self.cache.read().unwrap().network_id;
+        let mut paths = IndexSet::new();
+        for template in DerivationTemplate::all() {
+            let quantity = to_use_directly.get(&template).copied().unwrap_or_default() as u32
+                + fill_cache.quantity(template);
+            let indices =
+                self.next_entity_index_assigner
+                    .reserve(factor_source_id, template, quantity)?;
+            for index in indices {
+                paths.insert(template.derivation_path(network_id, index.index())?);
+            }
+        }
+        Ok(paths)
     }
 
-    async fn derive(&self, paths: DerivationPathPerFactorSource) -> Result<KeyDerivationOutcome> {
-        todo!()
+    /// Derives keys at `paths`, the factor sources in `required` MUST be derived,
+    /// i.e. not skipped by the user nor failed, since their instances are needed
+    /// to satisfy the query. Other factor sources are only derived to fill
+    /// the cache, so it is fine if the user skips them.
+    ///
+    /// The derived instances are reserved in the cache, whether they end up
+    /// cached or used directly.
+    async fn derive(
+        &self,
+        paths: DerivationPathPerFactorSource,
+        required: IndexSet<FactorSourceID>,
+    ) -> Result<KeyDerivationOutcome> {
+        let keys_collector = KeysCollector::new(
+            self.query.factor_sources(),
+            paths.paths(),
+            self.interactors.clone(),
+        )?;
+        let outcome = keys_collector.collect_keys().await;
+        outcome.ensure_derived(&required)?;
+        self.cache.read().unwrap().reserve(&outcome.all_instances());
+        Ok(outcome)
     }
+
+    /// Splits the `derived` instances of a single factor source into the first
+    /// ones, per template, to be used directly and the rest to be cached.
     fn split(
         &self,
-        from_cache: Option<HDFactorInstance>,
-        derived: KeyDerivationOutcome,
-    ) -> (ToUseDirectly, ToCache) {
-        todo!()
+        factor_source_id: FactorSourceID,
+        to_use_directly: &IndexMap<DerivationTemplate, usize>,
+        derived: IndexSet<HDFactorInstance>,
+    ) -> Result<(IndexSet<HDFactorInstance>, ToCache)> {
+        let network_id = self.cache.read().unwrap().network_id;
+        let mut use_directly = IndexSet::new();
+        let mut to_cache = IndexMap::new();
+        for template in DerivationTemplate::all() {
+            let mut instances = derived
+                .iter()
+                .filter(|f| f.derivation_path.network_id == network_id)
+                .filter(|f| template.matches(&f.derivation_path))
+                .cloned()
+                .sorted_by_key(|f| f.derivation_path.entity_index.index())
+                .collect::<IndexSet<_>>();
+            let quantity = to_use_directly.get(&template).copied().unwrap_or_default();
+            let cache = instances.split_off(quantity.min(instances.len()));
+            use_directly.extend(instances);
+            to_cache.insert(template, cache);
+        }
+        let to_cache =
+            CollectionsOfFactorInstances::with_instances(network_id, factor_source_id, to_cache)?;
+        Ok((use_directly, ToCache(to_cache)))
     }
-}
-impl FactorInstancesProvider {
-    async fn provide_account_veci(
+
+    /// For each factor source, takes the requested quantity of instances per
+    /// template from the cache, and derives the missing ones, if any derivation
+    /// is needed the cache is filled for every referenced factor source.
+    async fn provide_quantities(
         self,
-        factor_source: HDFactorSource,
+        quantities: IndexMap<FactorSourceID, IndexMap<DerivationTemplate, usize>>,
     ) -> Result<ProvidedInstances> {
-        let factor_source_id = factor_source.factor_source_id;
+        let mut from_cache = IndexMap::<FactorSourceID, IndexSet<HDFactorInstance>>::new();
+        let mut missing = IndexMap::<FactorSourceID, IndexMap<DerivationTemplate, usize>>::new();
+        let mut should_derive = false;
 
-        let maybe_cached = self
-            .cache
-            .write()
-            .unwrap()
-            .consume_account_veci(factor_source_id);
-        let mut maybe_next_index_for_derivation: Option<CAP26EntityIndex> = None;
-        let mut veci: Option<HDFactorInstance> = None;
-        let mut to_cache: Option<ToCache> = None;
-        if let Some(cached) = maybe_cached {
-            veci = Some(cached.instance.clone());
-            if cached.was_last_used {
-                // TODO: Must we check if `next` is in fact free??? Check against Profile that is...
-                // lets try skipping it for now
-                maybe_next_index_for_derivation =
-                    Some(cached.instance.derivation_path.entity_index.next());
+        for (factor_source_id, per_template) in quantities.iter() {
+            for (template, quantity) in per_template {
+                let cached =
+                    self.cache
+                        .read()
+                        .unwrap()
+                        .consume(*factor_source_id, *template, *quantity);
+                should_derive |= cached.was_last_used || cached.instances.len() < *quantity;
+                missing
+                    .entry(*factor_source_id)
+                    .or_default()
+                    .insert(*template, quantity - cached.instances.len());
+                from_cache
+                    .entry(*factor_source_id)
+                    .or_default()
+                    .extend(cached.instances);
             }
-        } else {
-            maybe_next_index_for_derivation = Some(
-                self.next_entity_index_assigner
-                    .next_account_veci(factor_source_id),
-            )
         }
-        assert!(
-            !(veci.is_none() && maybe_next_index_for_derivation.is_none()),
-            "Programmer error, both 'veci' and 'maybe_next_index_for_derivation' cannot be none."
-        );
-        if let Some(next_index_for_derivation) = maybe_next_index_for_derivation {
-            // furthermore, since we are deriving ANYWAY, we should also derive to Fill The Cache....
-            let fill_cache_maybe_over_estimated =
-                FillCacheQuantitiesForFactor::fill(factor_source_id);
-
-            let existing = self
-                .cache
-                .read()
-                .unwrap()
-                .peek_all_instances_for_factor_source(factor_source.factor_source_id.clone());
-
-            let fill_cache = fill_cache_maybe_over_estimated.subtracting_existing(existing);
 
-            let paths = self.paths_single_factor(
-                factor_source_id,
-                IndexMap::from_iter([(DerivationTemplate::AccountVeci, next_index_for_derivation)]),
-                fill_cache,
+        let mut derived_to_use = IndexMap::<FactorSourceID, IndexSet<HDFactorInstance>>::new();
+        if should_derive {
+            // Since we are deriving ANYWAY, we should also derive to Fill The Cache....
+            let fill_cache = FillCacheQuantitiesPerFactor::new(
+                missing
+                    .keys()
+                    .map(|id| {
+                        let existing = self
+                            .cache
+                            .read()
+                            .unwrap()
+                            .peek_all_instances_for_factor_source(*id);
+                        FillCacheQuantitiesForFactor::fill(*id).subtracting_existing(existing)
+                    })
+                    .collect::<Result<Vec<_>>>()?,
             );
+            let paths = missing
+                .iter()
+                .map(|(factor_source_id, to_use_directly)| {
+                    let paths = self.paths_single_factor(
+                        *factor_source_id,
+                        to_use_directly,
+                        fill_cache.quantities_for(*factor_source_id),
+                    )?;
+                    Ok((*factor_source_id, paths))
+                })
+                .collect::<Result<IndexMap<_, _>>>()?
+                .into_iter()
+                .filter(|(_, paths)| !paths.is_empty())
+                .collect::<IndexMap<_, _>>();
 
-            let derived = self.derive(paths).await?;
-            let (split_to_use_directly, split_to_cache) = self.split(veci, derived);
+            let required = missing
+                .iter()
+                .filter(|(_, per_template)| per_template.values().any(|q| *q > 0))
+                .map(|(id, _)| *id)
+                .collect::<IndexSet<_>>();
 
-            // unconditionally set `veci`, since `split` should handle logic of it
-            // being `None` or not.
-            veci = Some(split_to_use_directly.account_veci()?.instance());
-            to_cache = Some(split_to_cache);
-        }
-        let veci = veci.ok_or(CommonError::ExpectedValue)?;
-        if let Some(to_cache) = to_cache {
-            self.cache
-                .write()
-                .unwrap()
-                .append_for_factor(factor_source_id, to_cache)?;
+            let derived = self
+                .derive(DerivationPathPerFactorSource::new(paths), required)
+                .await?;
+
+            for (factor_source_id, to_use_directly) in missing.iter() {
+                let (use_directly, to_cache) = self.split(
+                    *factor_source_id,
+                    to_use_directly,
+                    derived.instances_for_factor_source(*factor_source_id),
+                )?;
+                derived_to_use.insert(*factor_source_id, use_directly);
+                self.cache
+                    .write()
+                    .unwrap()
+                    .append_for_factor(*factor_source_id, to_cache)?;
+            }
         }
+
+        let to_use_directly = quantities
+            .keys()
+            .flat_map(|id| {
+                let mut instances = from_cache.get(id).cloned().unwrap_or_default();
+                instances.extend(derived_to_use.get(id).cloned().unwrap_or_default());
+                instances
+            })
+            .collect::<IndexSet<_>>();
+
         let cache = self.cache.into_inner().unwrap();
-        Ok(ProvidedInstances::for_account_veci(cache, veci))
+        Ok(ProvidedInstances::new(
+            cache,
+            ToUseDirectly::new(to_use_directly),
+        ))
+    }
+}
+impl FactorInstancesProvider {
+    async fn provide_account_veci(
+        self,
+        factor_source: HDFactorSource,
+    ) -> Result<ProvidedInstances> {
+        self.provide_quantities(IndexMap::from_iter([(
+            factor_source.factor_source_id,
+            IndexMap::from_iter([(DerivationTemplate::AccountVeci, 1)]),
+        )]))
+        .await
+    }
+
+    async fn provide_identity_veci(
+        self,
+        factor_source: HDFactorSource,
+    ) -> Result<ProvidedInstances> {
+        self.provide_quantities(IndexMap::from_iter([(
+            factor_source.factor_source_id,
+            IndexMap::from_iter([(DerivationTemplate::IdentityVeci, 1)]),
+        )]))
+        .await
     }
 
     async fn provide_accounts_mfa(
-        &self,
+        self,
         number_of_instances_per_factor_source: usize,
         factor_sources: IndexSet<HDFactorSource>,
     ) -> Result<ProvidedInstances> {
-        todo!()
+        self.provide_quantities(
+            factor_sources
+                .into_iter()
+                .map(|f| {
+                    (
+                        f.factor_source_id,
+                        IndexMap::from_iter([(
+                            DerivationTemplate::AccountMfa,
+                            number_of_instances_per_factor_source,
+                        )]),
+                    )
+                })
+                .collect(),
+        )
+        .await
+    }
+
+    /// Derives account instances at `indices` in both key spaces and returns
+    /// the ones used on ledger. The unused ones are put in the cache - after
+    /// any already cached instance and up to `CACHE_SIZE` per template - so
+    /// that they need not be derived again when creating new accounts.
+    async fn provide_account_recovery_scan(
+        self,
+        factor_source: HDFactorSource,
+        indices: std::ops::Range<u32>,
+        on_ledger_key_usage: OnLedgerKeyUsage,
+    ) -> Result<ProvidedInstances> {
+        let factor_source_id = factor_source.factor_source_id;
+        let network_id = self.cache.read().unwrap().network_id;
+        let templates = [
+            DerivationTemplate::AccountVeci,
+            DerivationTemplate::AccountMfa,
+        ];
+        let paths = templates
+            .iter()
+            .flat_map(|template| {
+                indices
+                    .clone()
+                    .map(move |i| template.derivation_path(network_id, i))
+            })
+            .collect::<Result<IndexSet<_>>>()?;
+
+        let derived = self
+            .derive(
+                DerivationPathPerFactorSource::new(IndexMap::from_iter([(
+                    factor_source_id,
+                    paths,
+                )])),
+                IndexSet::from_iter([factor_source_id]),
+            )
+            .await?
+            .instances_for_factor_source(factor_source_id);
+
+        let used_keys = on_ledger_key_usage
+            .used_keys(derived.iter().map(|f| f.public_key).collect())
+            .await?;
+        let (used, unused): (IndexSet<_>, IndexSet<_>) = derived
+            .into_iter()
+            .partition(|f| used_keys.contains(&f.public_key));
+
+        let existing = self
+            .cache
+            .read()
+            .unwrap()
+            .peek_all_instances_for_factor_source(factor_source_id);
+        let fill_cache = FillCacheQuantitiesForFactor::fill(factor_source_id)
+            .subtracting_existing(existing.clone())?;
+        let to_cache = templates
+            .into_iter()
+            .map(|template| {
+                let last_cached = existing
+                    .as_ref()
+                    .and_then(|c| c.instances_for_template(template).last().cloned())
+                    .map(|f| f.derivation_path.entity_index.index());
+                let instances = unused
+                    .iter()
+                    .filter(|f| template.matches(&f.derivation_path))
+                    .filter(|f| {
+                        last_cached.is_none_or(|last| f.derivation_path.entity_index.index() > last)
+                    })
+                    .sorted_by_key(|f| f.derivation_path.entity_index.index())
+                    .take(fill_cache.quantity(template) as usize)
+                    .cloned()
+                    .collect::<IndexSet<_>>();
+                (template, instances)
+            })
+            .collect::<IndexMap<_, _>>();
+        self.cache.write().unwrap().append_for_factor(
+            factor_source_id,
+            ToCache(CollectionsOfFactorInstances::with_instances(
+                network_id,
+                factor_source_id,
+                to_cache,
+            )?),
+        )?;
+
+        let cache = self.cache.into_inner().unwrap();
+        Ok(ProvidedInstances::new(cache, ToUseDirectly::new(used)))
     }
 }
 
@@ -172,7 +617,7 @@ mod tests {
 
     #[actix::test]
     async fn cache_is_always_filled_account_veci() {
-        let cache = Arc::new(RwLock::new(FactorInstancesForEachNetworkCache::default()));
+        let cache = Arc::new(InMemoryFactorInstancesCacheStorage::default());
 
         let network = NetworkID::Mainnet;
         let profile = Profile::default();
@@ -185,12 +630,14 @@ mod tests {
             InstancesQuery::AccountVeci {
                 factor_source: bdfs.clone(),
             },
+            KeysDerivationInteractors::test(),
         )
         .await
         .unwrap();
 
         assert!(cache
-            .try_read()
+            .load()
+            .await
             .unwrap()
             .clone_for_network(network)
             .unwrap()
@@ -200,7 +647,395 @@ mod tests {
 
         assert_eq!(
             outcome.account_veci().unwrap().derivation_entity_index(),
+            CAP26EntityIndex::Unsecurified(0)
+        );
+    }
+
+    #[actix::test]
+    async fn concurrent_derivations_from_same_snapshot_are_not_both_merged() {
+        let mut snapshot = FactorInstancesForEachNetworkCache::default();
+        let network = NetworkID::Mainnet;
+        let provide = || async {
+            Sut::new(
+                snapshot.clone_for_network_or_empty(network),
+                snapshot.outstanding_instances(network),
+                Profile::default(),
+                InstancesQuery::AccountVeci {
+                    factor_source: HDFactorSource::sample(),
+                },
+                KeysDerivationInteractors::test(),
+            )
+            .unwrap()
+            ._provide()
+            .await
+            .unwrap()
+        };
+        let first = provide().await;
+        let second = provide().await;
+        assert_eq!(first.instances_to_be_used, second.instances_to_be_used);
+
+        snapshot.merge(first.cache_to_persist).unwrap();
+
+        assert_eq!(
+            snapshot.merge(second.cache_to_persist),
+            Err(CommonError::StaleCacheSnapshot)
+        );
+    }
+
+    #[actix::test]
+    async fn account_veci_uses_cache_when_not_empty() {
+        let cache = Arc::new(InMemoryFactorInstancesCacheStorage::default());
+        let network = NetworkID::Mainnet;
+        let bdfs = HDFactorSource::sample();
+        let provide = || {
+            Sut::provide(
+                cache.clone(),
+                network,
+                Profile::default(),
+                InstancesQuery::AccountVeci {
+                    factor_source: bdfs.clone(),
+                },
+                KeysDerivationInteractors::test(),
+            )
+        };
+
+        let first = provide().await.unwrap().account_veci().unwrap();
+        let second = provide().await.unwrap().account_veci().unwrap();
+
+        assert_eq!(
+            first.derivation_entity_index(),
+            CAP26EntityIndex::Unsecurified(0)
+        );
+        assert_eq!(
+            second.derivation_entity_index(),
             CAP26EntityIndex::Unsecurified(1)
         );
+        assert_eq!(
+            cache
+                .load()
+                .await
+                .unwrap()
+                .clone_for_network(network)
+                .unwrap()
+                .peek_all_instances_for_factor_source(bdfs.factor_source_id)
+                .unwrap()
+                .unsecurified_accounts()
+                .len(),
+            CACHE_SIZE as usize - 1
+        );
+    }
+
+    #[actix::test]
+    async fn identity_veci_uses_cache_filled_by_account_veci() {
+        let cache = Arc::new(InMemoryFactorInstancesCacheStorage::default());
+        let network = NetworkID::Mainnet;
+        let bdfs = HDFactorSource::sample();
+        let provide = |query| {
+            Sut::provide(
+                cache.clone(),
+                network,
+                Profile::default(),
+                query,
+                KeysDerivationInteractors::test(),
+            )
+        };
+
+        provide(InstancesQuery::AccountVeci {
+            factor_source: bdfs.clone(),
+        })
+        .await
+        .unwrap();
+        let identity_veci = provide(InstancesQuery::IdentityVeci {
+            factor_source: bdfs.clone(),
+        })
+        .await
+        .unwrap()
+        .identity_veci()
+        .unwrap();
+
+        // The first account veci was derived, so the cache was filled with
+        // identity vecis starting at the first index.
+        assert_eq!(
+            identity_veci.derivation_entity_index(),
+            CAP26EntityIndex::Unsecurified(0)
+        );
+        let cached = cache
+            .load()
+            .await
+            .unwrap()
+            .clone_for_network(network)
+            .unwrap()
+            .peek_all_instances_for_factor_source(bdfs.factor_source_id)
+            .unwrap();
+        assert_eq!(
+            cached.unsecurified_identities().len(),
+            CACHE_SIZE as usize - 1
+        );
+        assert_eq!(
+            cached
+                .unsecurified_identities()
+                .first()
+                .unwrap()
+                .derivation_entity_index(),
+            CAP26EntityIndex::Unsecurified(1)
+        );
+    }
+
+    #[actix::test]
+    async fn pre_derive_keys_fills_cache_on_every_network() {
+        #[derive(Default)]
+        struct CountingInteractor(std::sync::atomic::AtomicUsize);
+        #[async_trait::async_trait]
+        impl MonoFactorKeyDerivationInteractor for CountingInteractor {
+            async fn derive(
+                &self,
+                request: MonoFactorKeyDerivationRequest,
+            ) -> Result<KeyDerivationResponse> {
+                self.0.fetch_add(1, std::sync::atomic::Ordering::SeqCst);
+                MonoFactorKeyDerivationInteractor::derive(&TestDerivationInteractor, request).await
+            }
+        }
+        let counting = Arc::new(CountingInteractor::default());
+        let interactors = KeysDerivationInteractors::new(IndexMap::from_iter([(
+            FactorSourceKind::Ledger,
+            KeyDerivationInteractor::Serial(counting.clone()),
+        )]));
+        let cache = Arc::new(InMemoryFactorInstancesCacheStorage::default());
+        let ledger = HDFactorSource::sample_other();
+
+        let to_use_directly = Sut::provide(
+            cache.clone(),
+            NetworkID::Mainnet,
+            Profile::default(),
+            InstancesQuery::PreDeriveKeysForFactorSource {
+                factor_source: ledger.clone(),
+            },
+            interactors,
+        )
+        .await
+        .unwrap();
+
+        assert!(to_use_directly.instances().is_empty());
+        assert_eq!(counting.0.load(std::sync::atomic::Ordering::SeqCst), 1);
+        for network in NetworkID::all() {
+            assert!(cache
+                .load()
+                .await
+                .unwrap()
+                .clone_for_network(network)
+                .unwrap()
+                .peek_all_instances_for_factor_source(ledger.factor_source_id)
+                .unwrap()
+                .is_full());
+        }
+    }
+
+    #[actix::test]
+    async fn account_mfa_for_many_factor_sources() {
+        let cache = Arc::new(InMemoryFactorInstancesCacheStorage::default());
+        let network = NetworkID::Mainnet;
+        let bdfs = HDFactorSource::sample();
+        let ledger = HDFactorSource::sample_other();
+        let provide = |number_of_instances_per_factor_source| {
+            Sut::provide(
+                cache.clone(),
+                network,
+                Profile::default(),
+                InstancesQuery::AccountMfa {
+                    number_of_instances_per_factor_source,
+                    factor_sources: IndexSet::from_iter([bdfs.clone(), ledger.clone()]),
+                },
+                KeysDerivationInteractors::test(),
+            )
+        };
+
+        let per_factor_source = provide(3)
+            .await
+            .unwrap()
+            .account_mfa_per_factor_source()
+            .unwrap();
+
+        assert_eq!(
+            per_factor_source.keys().cloned().collect_vec(),
+            vec![bdfs.factor_source_id, ledger.factor_source_id]
+        );
+        for instances in per_factor_source.values() {
+            assert_eq!(
+                instances
+                    .iter()
+                    .map(|f| f.derivation_entity_index())
+                    .collect_vec(),
+                (0..3).map(CAP26EntityIndex::Securified).collect_vec()
+            );
+        }
+        for f in [&bdfs, &ledger] {
+            assert!(cache
+                .load()
+                .await
+                .unwrap()
+                .clone_for_network(network)
+                .unwrap()
+                .peek_all_instances_for_factor_source(f.factor_source_id)
+                .unwrap()
+                .is_full());
+        }
+
+        // More than in cache: takes all cached, derives the rest and refills.
+        let per_factor_source = provide(CACHE_SIZE as usize + 2)
+            .await
+            .unwrap()
+            .account_mfa_per_factor_source()
+            .unwrap();
+        assert_eq!(
+            per_factor_source[&ledger.factor_source_id]
+                .iter()
+                .map(|f| f.derivation_entity_index())
+                .collect_vec(),
+            (3..CACHE_SIZE + 5)
+                .map(CAP26EntityIndex::Securified)
+                .collect_vec()
+        );
+        assert!(cache
+            .load()
+            .await
+            .unwrap()
+            .clone_for_network(network)
+            .unwrap()
+            .peek_all_instances_for_factor_source(ledger.factor_source_id)
+            .unwrap()
+            .is_full());
+    }
+
+    #[actix::test]
+    async fn account_mfa_fails_if_needed_factor_source_is_skipped() {
+        struct SkippingInteractor;
+        #[async_trait::async_trait]
+        impl MonoFactorKeyDerivationInteractor for SkippingInteractor {
+            async fn derive(
+                &self,
+                request: MonoFactorKeyDerivationRequest,
+            ) -> Result<KeyDerivationResponse> {
+                Ok(KeyDerivationResponse::skipping(IndexSet::from_iter([
+                    request.factor_source.factor_source_id,
+                ])))
+            }
+        }
+        let mut interactors = IndexMap::from_iter([(
+            FactorSourceKind::Ledger,
+            KeyDerivationInteractor::Serial(Arc::new(SkippingInteractor)),
+        )]);
+        interactors.insert(
+            FactorSourceKind::Device,
+            KeysDerivationInteractors::test()
+                .interactor_for(FactorSourceKind::Device)
+                .unwrap(),
+        );
+
+        let cache = Arc::new(InMemoryFactorInstancesCacheStorage::default());
+        let result = Sut::provide(
+            cache.clone(),
+            NetworkID::Mainnet,
+            Profile::default(),
+            InstancesQuery::AccountMfa {
+                number_of_instances_per_factor_source: 1,
+                factor_sources: IndexSet::from_iter([
+                    HDFactorSource::sample(),
+                    HDFactorSource::sample_other(),
+                ]),
+            },
+            KeysDerivationInteractors::new(interactors),
+        )
+        .await;
+
+        assert_eq!(
+            result,
+            Err(CommonError::FactorSourceSkippedByUser {
+                factor_source_id: FactorSourceID::sample_other()
+            })
+        );
+        assert!(cache.load().await.unwrap().networks.is_empty());
+    }
+
+    #[actix::test]
+    async fn account_recovery_scan_returns_used_and_caches_unused() {
+        let cache = Arc::new(InMemoryFactorInstancesCacheStorage::default());
+        let network = NetworkID::Mainnet;
+        let bdfs = HDFactorSource::sample();
+        let used = bdfs
+            .derive(&IndexSet::from_iter([
+                DerivationTemplate::AccountVeci
+                    .derivation_path(network, 1)
+                    .unwrap(),
+                DerivationTemplate::AccountMfa
+                    .derivation_path(network, 3)
+                    .unwrap(),
+            ]))
+            .unwrap();
+        let on_ledger_key_usage = OnLedgerKeyUsage::new(Arc::new(TestIsKeyUsedOnLedger::new(
+            used.iter().map(|f| f.public_key).collect(),
+        )));
+
+        let found = Sut::provide(
+            cache.clone(),
+            network,
+            None,
+            InstancesQuery::AccountRecoveryScan {
+                factor_source: bdfs.clone(),
+                indices: 0..5,
+                on_ledger_key_usage,
+            },
+            KeysDerivationInteractors::test(),
+        )
+        .await
+        .unwrap();
+
+        assert_eq!(found.instances(), used);
+        let cached = cache
+            .load()
+            .await
+            .unwrap()
+            .clone_for_network(network)
+            .unwrap()
+            .peek_all_instances_for_factor_source(bdfs.factor_source_id)
+            .unwrap();
+        assert_eq!(
+            cached
+                .unsecurified_accounts()
+                .iter()
+                .map(|f| f.derivation_entity_index())
+                .collect_vec(),
+            [0, 2, 3, 4].map(CAP26EntityIndex::Unsecurified).to_vec()
+        );
+        assert_eq!(
+            cached
+                .securified_accounts()
+                .iter()
+                .map(|f| f.derivation_entity_index())
+                .collect_vec(),
+            [0, 1, 2, 4].map(CAP26EntityIndex::Securified).to_vec()
+        );
+        assert!(cached.unsecurified_identities().is_empty());
+    }
+
+    #[actix::test]
+    async fn account_recovery_scan_beyond_key_space_is_err() {
+        let cache = Arc::new(InMemoryFactorInstancesCacheStorage::default());
+        let result = Sut::provide(
+            cache.clone(),
+            NetworkID::Mainnet,
+            None,
+            InstancesQuery::AccountRecoveryScan {
+                factor_source: HDFactorSource::sample(),
+                indices: KEY_SPACE_SIZE - 1..KEY_SPACE_SIZE + 1,
+                on_ledger_key_usage: OnLedgerKeyUsage::new(Arc::new(TestIsKeyUsedOnLedger::new(
+                    IndexSet::new(),
+                ))),
+            },
+            KeysDerivationInteractors::test(),
+        )
+        .await;
+
+        assert_eq!(result, Err(CommonError::CAP26EntityIndexOverflow));
+        assert!(cache.load().await.unwrap().networks.is_empty());
     }
 }
@@ -6,31 +6,92 @@ pub const CACHE_SIZE: u32 = 30;
 pub struct FillCacheQuantitiesForFactor {
     pub factor_source_id: FactorSourceID,
 
-    /// Number of "account veci" instances to derive, using
-    /// `factor_source_id` as the factor source
-    pub account_vecis: u32,
-
-    /// Number of "account mfa" instances to derive
-    /// `factor_source_id` as the factor source
-    pub account_mfa: u32,
+    /// Number of instances to derive per `DerivationTemplate`, using
+    /// `factor_source_id` as the factor source, a missing template
+    /// means zero instances.
+    pub per_template: IndexMap<DerivationTemplate, u32>,
 }
 impl FillCacheQuantitiesForFactor {
+    /// `CACHE_SIZE` instances for every `DerivationTemplate`.
     pub fn fill(factor_source_id: FactorSourceID) -> Self {
-        Self::new(factor_source_id, CACHE_SIZE, CACHE_SIZE)
+        Self::new(
+            factor_source_id,
+            DerivationTemplate::all()
+                .into_iter()
+                .map(|template| (template, CACHE_SIZE))
+                .collect(),
+        )
     }
-    pub fn new(factor_source_id: FactorSourceID, account_vecis: u32, account_mfa: u32) -> Self {
+    pub fn new(
+        factor_source_id: FactorSourceID,
+        per_template: IndexMap<DerivationTemplate, u32>,
+    ) -> Self {
         Self {
             factor_source_id,
-            account_mfa,
-            account_vecis,
+            per_template,
         }
     }
 
+    /// The number of instances to derive for `template`.
+    pub fn quantity(&self, template: DerivationTemplate) -> u32 {
+        self.per_template
+            .get(&template)
+            .copied()
+            .unwrap_or_default()
+    }
+
+    /// The total number of instances to derive, over all templates.
+    pub fn total(&self) -> u32 {
+        self.per_template.values().sum()
+    }
+
+    /// Lowers the quantity of each template by the number of instances
+    /// `existing` already holds for it, never going below zero. Fails if
+    /// `existing` is for another factor source.
     pub fn subtracting_existing(
         self,
         existing: impl Into<Option<CollectionsOfFactorInstances>>,
-    ) -> Self {
-        todo!()
+    ) -> Result<Self> {
+        let Some(existing) = existing.into() else {
+            return Ok(self);
+        };
+        if existing.factor_source_id() != self.factor_source_id {
+            return Err(CommonError::FactorSourceDiscrepancy {
+                expected: self.factor_source_id,
+                found: existing.factor_source_id(),
+            });
+        }
+        let per_template = self
+            .per_template
+            .iter()
+            .map(|(template, quantity)| {
+                let existing = existing.instances_for_template(*template).len() as u32;
+                (*template, quantity.saturating_sub(existing))
+            })
+            .collect();
+        Ok(Self::new(self.factor_source_id, per_template))
+    }
+
+    /// Adds the quantities of `other` to the quantities of `self`, template
+    /// by template. Fails if `other` is for another factor source.
+    pub fn merging(self, other: Self) -> Result<Self> {
+        if other.factor_source_id != self.factor_source_id {
+            return Err(CommonError::FactorSourceDiscrepancy {
+                expected: self.factor_source_id,
+                found: other.factor_source_id,
+            });
+        }
+        Ok(self.adding(other))
+    }
+
+    /// Like `merging`, `other` MUST be for the same factor source.
+    fn adding(self, other: Self) -> Self {
+        let mut per_template = self.per_template;
+        for (template, quantity) in other.per_template {
+            let q = per_template.entry(template).or_default();
+            *q = q.saturating_add(quantity);
+        }
+        Self::new(self.factor_source_id, per_template)
     }
 }
 
@@ -40,10 +101,136 @@ pub struct FillCacheQuantitiesPerFactor {
     pub per_factor_source: IndexMap<FactorSourceID, FillCacheQuantitiesForFactor>,
 }
 impl FillCacheQuantitiesPerFactor {
+    /// Quantities for the same factor source are merged.
+    pub fn new(items: impl IntoIterator<Item = FillCacheQuantitiesForFactor>) -> Self {
+        items
+            .into_iter()
+            .fold(Self::empty(), |acc, item| acc.merging(Self::just(item)))
+    }
+    pub fn empty() -> Self {
+        Self {
+            hidden_constructor: HiddenConstructor,
+            per_factor_source: IndexMap::new(),
+        }
+    }
     pub fn just(item: FillCacheQuantitiesForFactor) -> Self {
         Self {
             hidden_constructor: HiddenConstructor,
-            per_factor_source: IndexMap::from_iter([(item.factor_source_id.clone(), item)]),
+            per_factor_source: IndexMap::from_iter([(item.factor_source_id, item)]),
         }
     }
+
+    /// Merges `other` into `self`, adding the quantities of factor sources
+    /// present in both, so that a single derivation can top up the cache of
+    /// many factor sources and templates at once.
+    pub fn merging(self, other: Self) -> Self {
+        let mut per_factor_source = self.per_factor_source;
+        for (factor_source_id, quantities) in other.per_factor_source {
+            let merged = match per_factor_source.get(&factor_source_id) {
+                Some(existing) => existing.clone().adding(quantities),
+                None => quantities,
+            };
+            per_factor_source.insert(factor_source_id, merged);
+        }
+        Self {
+            hidden_constructor: HiddenConstructor,
+            per_factor_source,
+        }
+    }
+
+    /// The quantities for `factor_source_id`, all zero if unknown.
+    pub fn quantities_for(&self, factor_source_id: FactorSourceID) -> FillCacheQuantitiesForFactor {
+        self.per_factor_source
+            .get(&factor_source_id)
+            .cloned()
+            .unwrap_or_else(|| FillCacheQuantitiesForFactor::new(factor_source_id, IndexMap::new()))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    type Sut = FillCacheQuantitiesPerFactor;
+
+    #[test]
+    fn subtracting_existing_per_template() {
+        let id = FactorSourceID::sample();
+        let network = NetworkID::Mainnet;
+        let hd = HDFactorSource::sample();
+        let existing = CollectionsOfFactorInstances::with_instances(
+            network,
+            id,
+            IndexMap::from_iter([(
+                DerivationTemplate::AccountMfa,
+                hd.derive(
+                    &(0..3)
+                        .map(|i| {
+                            DerivationTemplate::AccountMfa
+                                .derivation_path(network, i)
+                                .unwrap()
+                        })
+                        .collect(),
+                )
+                .unwrap(),
+            )]),
+        )
+        .unwrap();
+
+        let sut = FillCacheQuantitiesForFactor::fill(id)
+            .subtracting_existing(existing)
+            .unwrap();
+
+        assert_eq!(sut.quantity(DerivationTemplate::AccountMfa), CACHE_SIZE - 3);
+        assert_eq!(sut.quantity(DerivationTemplate::AccountVeci), CACHE_SIZE);
+        assert_eq!(sut.total(), 5 * CACHE_SIZE - 3);
+    }
+
+    #[test]
+    fn merging_adds_quantities_of_same_factor_source() {
+        let id = FactorSourceID::sample();
+        let other = FactorSourceID::sample_other();
+        let veci = |id, q| {
+            FillCacheQuantitiesForFactor::new(
+                id,
+                IndexMap::from_iter([(DerivationTemplate::AccountVeci, q)]),
+            )
+        };
+        let mfa = FillCacheQuantitiesForFactor::new(
+            id,
+            IndexMap::from_iter([(DerivationTemplate::AccountMfa, 2)]),
+        );
+
+        let sut = Sut::new([veci(id, 1), veci(other, 4)]).merging(Sut::new([veci(id, 2), mfa]));
+
+        assert_eq!(
+            sut.per_factor_source.keys().cloned().collect_vec(),
+            vec![id, other]
+        );
+        let for_id = sut.quantities_for(id);
+        assert_eq!(for_id.quantity(DerivationTemplate::AccountVeci), 3);
+        assert_eq!(for_id.quantity(DerivationTemplate::AccountMfa), 2);
+        assert_eq!(sut.quantities_for(other).total(), 4);
+    }
+
+    #[test]
+    fn other_factor_source_is_err() {
+        let id = FactorSourceID::sample();
+        let other = FactorSourceID::sample_other();
+        let discrepancy = Err(CommonError::FactorSourceDiscrepancy {
+            expected: id,
+            found: other,
+        });
+        assert_eq!(
+            FillCacheQuantitiesForFactor::fill(id)
+                .merging(FillCacheQuantitiesForFactor::fill(other)),
+            discrepancy
+        );
+        assert_eq!(
+            FillCacheQuantitiesForFactor::fill(id).subtracting_existing(
+                CollectionsOfFactorInstances::empty(NetworkID::Mainnet, other)
+            ),
+            discrepancy
+        );
+    }
 }
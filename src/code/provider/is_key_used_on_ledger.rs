@@ -0,0 +1,57 @@
+use std::sync::Arc;
+
+use crate::prelude::*;
+
+/// Answers which public keys are used on ledger, i.e. referenced by some
+/// entity, used by the Account Recovery Scan to discover accounts.
+#[async_trait::async_trait]
+pub trait IsKeyUsedOnLedger: Send + Sync {
+    /// Returns the subset of `public_keys` which are used on ledger.
+    async fn used_keys(&self, public_keys: IndexSet<PublicKey>) -> Result<IndexSet<PublicKey>>;
+}
+
+/// A shared `IsKeyUsedOnLedger`, which can be put inside an `InstancesQuery`,
+/// two are equal only if they are the very same instance.
+#[derive(Clone)]
+pub struct OnLedgerKeyUsage(Arc<dyn IsKeyUsedOnLedger>);
+impl OnLedgerKeyUsage {
+    pub fn new(is_key_used_on_ledger: Arc<dyn IsKeyUsedOnLedger>) -> Self {
+        Self(is_key_used_on_ledger)
+    }
+    pub async fn used_keys(&self, public_keys: IndexSet<PublicKey>) -> Result<IndexSet<PublicKey>> {
+        self.0.used_keys(public_keys).await
+    }
+}
+impl std::fmt::Debug for OnLedgerKeyUsage {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str("OnLedgerKeyUsage")
+    }
+}
+impl PartialEq for OnLedgerKeyUsage {
+    fn eq(&self, other: &Self) -> bool {
+        Arc::ptr_eq(&self.0, &other.0)
+    }
+}
+impl Eq for OnLedgerKeyUsage {}
+
+/// An in-memory `IsKeyUsedOnLedger` which considers exactly the keys
+/// in `used` to be used on ledger.
+#[derive(Clone, Debug, Default)]
+pub struct TestIsKeyUsedOnLedger {
+    pub used: IndexSet<PublicKey>,
+}
+impl TestIsKeyUsedOnLedger {
+    pub fn new(used: IndexSet<PublicKey>) -> Self {
+        Self { used }
+    }
+}
+
+#[async_trait::async_trait]
+impl IsKeyUsedOnLedger for TestIsKeyUsedOnLedger {
+    async fn used_keys(&self, public_keys: IndexSet<PublicKey>) -> Result<IndexSet<PublicKey>> {
+        Ok(public_keys
+            .into_iter()
+            .filter(|k| self.used.contains(k))
+            .collect())
+    }
+}
@@ -1,9 +1,11 @@
 mod factor_instances_provider;
 mod fill_cache;
+mod is_key_used_on_ledger;
 mod provided_instances;
 mod query;
 
 pub use factor_instances_provider::*;
 pub use fill_cache::*;
+pub use is_key_used_on_ledger::*;
 pub use provided_instances::*;
 pub use query::*;
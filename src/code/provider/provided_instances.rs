@@ -2,6 +2,7 @@ use crate::prelude::*;
 
 #[derive(Debug)]
 pub struct ProvidedInstances {
+    #[allow(dead_code)]
     hidden_constructor: HiddenConstructor,
 
     /// The caller of FactorInstancesProvider::provide MUST override their
@@ -20,7 +21,11 @@ impl ProvidedInstances {
         cache: FactorInstancesForSpecificNetworkCache,
         to_use_directly: ToUseDirectly,
     ) -> Self {
-        todo!()
+        Self {
+            hidden_constructor: HiddenConstructor,
+            cache_to_persist: cache,
+            instances_to_be_used: to_use_directly,
+        }
     }
     pub fn for_account_veci(
         cache: FactorInstancesForSpecificNetworkCache,
@@ -9,6 +9,13 @@ pub enum InstancesQuery {
         factor_source: HDFactorSource,
     },
 
+    /// Uses the "next" derivation entity index for the derivation path
+    /// The network is already known by the FactorInstancesProvider
+    IdentityVeci {
+        /// The factor to use to derive the instance, typically the main BDFS.
+        factor_source: HDFactorSource,
+    },
+
     /// Uses a range of derivation paths, starting at the next, per factor source
     /// The network is already known by the FactorInstancesProvider
     ///
@@ -23,5 +30,44 @@ pub enum InstancesQuery {
         number_of_instances_per_factor_source: usize,
         factor_sources: IndexSet<HDFactorSource>,
     },
-    // PreDeriveKeysForFactorSource
+
+    /// Fills the cache for every `DerivationTemplate` on **every** network,
+    /// typically used when the user adds a new factor source, so that the
+    /// user does not need to use it again - e.g. connect a Ledger device -
+    /// until the cache is depleted.
+    PreDeriveKeysForFactorSource {
+        /// The newly added factor source
+        factor_source: HDFactorSource,
+    },
+
+    /// Derives the instances at `indices` in **both** the unsecurified and
+    /// the securified key space for account transaction signing, and asks
+    /// `on_ledger_key_usage` which of them are used on ledger, those are the
+    /// discovered accounts. The unused ones are put in the cache.
+    ///
+    /// Used during Onboarding, so there is no Profile.
+    /// The network is already known by the FactorInstancesProvider
+    AccountRecoveryScan {
+        /// The factor source to recover accounts for.
+        factor_source: HDFactorSource,
+        /// The indices to scan, in each key space, typically `0..CACHE_SIZE`
+        /// followed by the next range if any account was found.
+        indices: std::ops::Range<u32>,
+        on_ledger_key_usage: OnLedgerKeyUsage,
+    },
+}
+
+impl InstancesQuery {
+    /// The factor sources used by this query, needed to derive keys.
+    pub fn factor_sources(&self) -> IndexSet<HDFactorSource> {
+        match self {
+            InstancesQuery::AccountVeci { factor_source }
+            | InstancesQuery::IdentityVeci { factor_source }
+            | InstancesQuery::PreDeriveKeysForFactorSource { factor_source }
+            | InstancesQuery::AccountRecoveryScan { factor_source, .. } => {
+                IndexSet::from_iter([factor_source.clone()])
+            }
+            InstancesQuery::AccountMfa { factor_sources, .. } => factor_sources.clone(),
+        }
+    }
 }
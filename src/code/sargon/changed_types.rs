@@ -1,26 +1,123 @@
 use crate::prelude::*;
 
+/// BIP32 hardened derivation, every CAP26 path component is hardened.
+pub const HARDENED_OFFSET: u32 = 1 << 31;
+
+/// Securified indices are offset by this, so that the two key spaces never
+/// overlap: `[0, 2^30)` is unsecurified, `[2^30, 2^31)` is securified.
+pub const SECURIFIED_OFFSET: u32 = 1 << 30;
+
+/// The number of indices in each key space.
+pub const KEY_SPACE_SIZE: u32 = SECURIFIED_OFFSET;
+
+/// BIP44 purpose, the first component of every CAP26 path.
+pub const PURPOSE: u32 = 44;
+
+/// The SLIP44 coin type of Radix, the second component of every CAP26 path.
+pub const COIN_TYPE: u32 = 1022;
+
 #[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
 pub enum KeySpace {
     Unsecurified,
     Securified,
 }
+impl KeySpace {
+    /// The offset of the first index of this key space, before hardening.
+    pub fn offset(&self) -> u32 {
+        match self {
+            KeySpace::Unsecurified => 0,
+            KeySpace::Securified => SECURIFIED_OFFSET,
+        }
+    }
+}
 
+/// The index of an entity, local to its key space, i.e. in `[0, 2^30)`.
 #[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
 pub enum CAP26EntityIndex {
     Securified(u32),
     Unsecurified(u32),
 }
 impl CAP26EntityIndex {
-    pub fn next(&self) -> Self {
-        todo!()
+    /// Unchecked, only used where `index` is known to be within a key space,
+    /// use `try_new` otherwise.
+    pub(crate) fn new(key_space: KeySpace, index: u32) -> Self {
+        match key_space {
+            KeySpace::Unsecurified => Self::Unsecurified(index),
+            KeySpace::Securified => Self::Securified(index),
+        }
+    }
+
+    /// Fails if `index` is not within a key space.
+    pub fn try_new(key_space: KeySpace, index: u32) -> Result<Self> {
+        if index >= KEY_SPACE_SIZE {
+            return Err(CommonError::CAP26EntityIndexOverflow);
+        }
+        Ok(Self::new(key_space, index))
+    }
+
+    /// Fails if `self` was built with an index outside of its key space.
+    pub fn validated(self) -> Result<Self> {
+        Self::try_new(self.key_space(), self.index())
     }
+
+    pub fn index(&self) -> u32 {
+        match self {
+            CAP26EntityIndex::Securified(i) | CAP26EntityIndex::Unsecurified(i) => *i,
+        }
+    }
+
+    /// The following index in the same key space, fails at the end of it.
+    pub fn next(&self) -> Result<Self> {
+        self.checked_add(1)
+    }
+
+    /// The index `n` steps further in the same key space, fails rather than
+    /// crossing into the other key space.
+    pub fn checked_add(&self, n: u32) -> Result<Self> {
+        let index = self
+            .index()
+            .checked_add(n)
+            .ok_or(CommonError::CAP26EntityIndexOverflow)?;
+        Self::try_new(self.key_space(), index)
+    }
+
+    /// The `quantity` consecutive indices starting at - and including - `self`.
+    pub fn range(&self, quantity: u32) -> Result<IndexSet<Self>> {
+        if quantity > 0 {
+            self.checked_add(quantity - 1)?;
+        }
+        Ok((0..quantity)
+            .map(|n| Self::new(self.key_space(), self.index() + n))
+            .collect())
+    }
+
     pub fn key_space(&self) -> KeySpace {
         match self {
             CAP26EntityIndex::Securified(_) => KeySpace::Securified,
             CAP26EntityIndex::Unsecurified(_) => KeySpace::Unsecurified,
         }
     }
+
+    /// The hardened BIP32 path component, with securified indices offset by
+    /// `SECURIFIED_OFFSET`, fails rather than wrapping into the other key
+    /// space if the index is not within its key space.
+    pub fn to_hardened_u32(&self) -> Result<u32> {
+        let index = self.validated()?.index();
+        Ok((self.key_space().offset() + index) | HARDENED_OFFSET)
+    }
+
+    /// The inverse of `to_hardened_u32`, fails if `value` is not hardened.
+    pub fn from_hardened_u32(value: u32) -> Result<Self> {
+        if value & HARDENED_OFFSET == 0 {
+            return Err(CommonError::NonHardenedIndex);
+        }
+        let unhardened = value & !HARDENED_OFFSET;
+        if unhardened >= SECURIFIED_OFFSET {
+            Ok(Self::Securified(unhardened - SECURIFIED_OFFSET))
+        } else {
+            Ok(Self::Unsecurified(unhardened))
+        }
+    }
 }
 
 #[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
@@ -32,7 +129,256 @@ pub struct DerivationPath {
 }
 
 impl DerivationPath {
+    pub fn new(
+        network_id: NetworkID,
+        entity_kind: CAP26EntityKind,
+        key_kind: CAP26KeyKind,
+        entity_index: CAP26EntityIndex,
+    ) -> Self {
+        Self {
+            network_id,
+            entity_kind,
+            key_kind,
+            entity_index,
+        }
+    }
     pub fn key_space(&self) -> KeySpace {
         self.entity_index.key_space()
     }
 }
+
+/// `m/44H/1022H/<network>H/<entity kind>H/<key kind>H/<index>H`, where a
+/// securified index is marked `S` instead of `H`, e.g. `0S` is the first
+/// securified index, i.e. `2^30 + 0` hardened.
+impl std::fmt::Display for DerivationPath {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let marker = match self.key_space() {
+            KeySpace::Unsecurified => "H",
+            KeySpace::Securified => "S",
+        };
+        write!(
+            f,
+            "m/{}H/{}H/{}H/{}H/{}H/{}{}",
+            PURPOSE,
+            COIN_TYPE,
+            self.network_id.discriminant(),
+            self.entity_kind.discriminant(),
+            self.key_kind.discriminant(),
+            self.entity_index.index(),
+            marker
+        )
+    }
+}
+
+/// Accepts `H` or `'` as hardened marker, and `S` for securified indices, a
+/// hardened index of at least `2^30` is also read as securified.
+impl std::str::FromStr for DerivationPath {
+    type Err = CommonError;
+
+    fn from_str(s: &str) -> Result<Self> {
+        let mut components = s.split('/');
+        if components.next() != Some("m") {
+            return Err(CommonError::InvalidDerivationPathPrefix);
+        }
+        let components = components.collect_vec();
+        if components.len() != 6 {
+            return Err(CommonError::InvalidDerivationPathLength {
+                expected: 6,
+                found: components.len(),
+            });
+        }
+        let mut hardened = Vec::with_capacity(6);
+        for (position, component) in components.into_iter().enumerate() {
+            let (digits, securified) = if let Some(d) = component.strip_suffix('S') {
+                (d, true)
+            } else if let Some(d) = component
+                .strip_suffix('H')
+                .or_else(|| component.strip_suffix('\''))
+            {
+                (d, false)
+            } else {
+                return Err(CommonError::NonHardenedDerivationPathComponent { position });
+            };
+            let value = digits
+                .parse::<u32>()
+                .map_err(|_| CommonError::InvalidDerivationPathComponent { position })?;
+            if securified && position != 5 {
+                return Err(CommonError::InvalidDerivationPathComponent { position });
+            }
+            if value >= HARDENED_OFFSET {
+                return Err(CommonError::InvalidDerivationPathComponent { position });
+            }
+            hardened.push((value, securified));
+        }
+        let [purpose, coin_type, network, entity_kind, key_kind, (index, securified)] =
+            hardened[..]
+        else {
+            unreachable!("Length checked above");
+        };
+        if purpose.0 != PURPOSE {
+            return Err(CommonError::InvalidDerivationPathPurpose { found: purpose.0 });
+        }
+        if coin_type.0 != COIN_TYPE {
+            return Err(CommonError::InvalidDerivationPathCoinType { found: coin_type.0 });
+        }
+        let entity_index = if securified {
+            CAP26EntityIndex::try_new(KeySpace::Securified, index)?
+        } else {
+            CAP26EntityIndex::from_hardened_u32(index | HARDENED_OFFSET)?
+        };
+        Ok(Self::new(
+            NetworkID::from_discriminant(network.0)?,
+            CAP26EntityKind::from_discriminant(entity_kind.0)?,
+            CAP26KeyKind::from_discriminant(key_kind.0)?,
+            entity_index,
+        ))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    type Sut = CAP26EntityIndex;
+
+    #[test]
+    fn hardened_u32_roundtrip() {
+        for (sut, hardened) in [
+            (Sut::Unsecurified(0), 0x8000_0000),
+            (Sut::Unsecurified(5), 0x8000_0005),
+            (Sut::Securified(0), 0xc000_0000),
+            (Sut::Securified(KEY_SPACE_SIZE - 1), 0xffff_ffff),
+        ] {
+            assert_eq!(sut.to_hardened_u32(), Ok(hardened));
+            assert_eq!(Sut::from_hardened_u32(hardened), Ok(sut));
+        }
+        for sut in [
+            Sut::Unsecurified(KEY_SPACE_SIZE),
+            Sut::Securified(KEY_SPACE_SIZE),
+            Sut::Securified(u32::MAX),
+        ] {
+            assert_eq!(
+                sut.to_hardened_u32(),
+                Err(CommonError::CAP26EntityIndexOverflow)
+            );
+        }
+        assert_eq!(
+            Sut::from_hardened_u32(5),
+            Err(CommonError::NonHardenedIndex)
+        );
+    }
+
+    #[test]
+    fn arithmetic_never_crosses_key_space() {
+        let last = Sut::Unsecurified(KEY_SPACE_SIZE - 1);
+        assert_eq!(Sut::Securified(1).next(), Ok(Sut::Securified(2)));
+        assert_eq!(last.next(), Err(CommonError::CAP26EntityIndexOverflow));
+        assert_eq!(
+            Sut::Securified(1).checked_add(u32::MAX),
+            Err(CommonError::CAP26EntityIndexOverflow)
+        );
+        assert_eq!(
+            Sut::try_new(KeySpace::Securified, KEY_SPACE_SIZE),
+            Err(CommonError::CAP26EntityIndexOverflow)
+        );
+        assert_eq!(
+            Sut::Securified(3)
+                .range(2)
+                .unwrap()
+                .into_iter()
+                .collect_vec(),
+            vec![Sut::Securified(3), Sut::Securified(4)]
+        );
+        assert_eq!(last.range(1).unwrap().len(), 1);
+        assert_eq!(last.range(2), Err(CommonError::CAP26EntityIndexOverflow));
+        assert!(last.range(0).unwrap().is_empty());
+    }
+
+    #[test]
+    fn derivation_path_string_roundtrip() {
+        for (path, s) in [
+            (
+                DerivationTemplate::AccountVeci
+                    .derivation_path(NetworkID::Mainnet, 0)
+                    .unwrap(),
+                "m/44H/1022H/1H/525H/1460H/0H",
+            ),
+            (
+                DerivationTemplate::IdentityMfa
+                    .derivation_path(NetworkID::Testnet, 7)
+                    .unwrap(),
+                "m/44H/1022H/2H/618H/1460H/7S",
+            ),
+            (
+                DerivationTemplate::AccountRola
+                    .derivation_path(NetworkID::Mainnet, 1)
+                    .unwrap(),
+                "m/44H/1022H/1H/525H/1678H/1S",
+            ),
+        ] {
+            assert_eq!(path.to_string(), s);
+            assert_eq!(s.parse::<DerivationPath>(), Ok(path));
+        }
+        assert_eq!(
+            "m/44'/1022'/1'/525'/1460'/1073741825'".parse::<DerivationPath>(),
+            Ok(DerivationTemplate::AccountMfa
+                .derivation_path(NetworkID::Mainnet, 1)
+                .unwrap())
+        );
+    }
+
+    #[test]
+    fn derivation_path_parse_errors() {
+        for (s, error) in [
+            (
+                "44H/1022H/1H/525H/1460H/0H",
+                CommonError::InvalidDerivationPathPrefix,
+            ),
+            (
+                "m/44H/1022H/1H/525H/1460H",
+                CommonError::InvalidDerivationPathLength {
+                    expected: 6,
+                    found: 5,
+                },
+            ),
+            (
+                "m/44H/1022H/1H/525H/1460H/0",
+                CommonError::NonHardenedDerivationPathComponent { position: 5 },
+            ),
+            (
+                "m/44H/1022H/1H/xH/1460H/0H",
+                CommonError::InvalidDerivationPathComponent { position: 3 },
+            ),
+            (
+                "m/44H/1022S/1H/525H/1460H/0H",
+                CommonError::InvalidDerivationPathComponent { position: 1 },
+            ),
+            (
+                "m/45H/1022H/1H/525H/1460H/0H",
+                CommonError::InvalidDerivationPathPurpose { found: 45 },
+            ),
+            (
+                "m/44H/1021H/1H/525H/1460H/0H",
+                CommonError::InvalidDerivationPathCoinType { found: 1021 },
+            ),
+            (
+                "m/44H/1022H/9H/525H/1460H/0H",
+                CommonError::UnknownNetworkID { discriminant: 9 },
+            ),
+            (
+                "m/44H/1022H/1H/526H/1460H/0H",
+                CommonError::UnknownEntityKind { discriminant: 526 },
+            ),
+            (
+                "m/44H/1022H/1H/525H/1461H/0H",
+                CommonError::UnknownKeyKind { discriminant: 1461 },
+            ),
+            (
+                "m/44H/1022H/1H/525H/1460H/1073741824S",
+                CommonError::CAP26EntityIndexOverflow,
+            ),
+        ] {
+            assert_eq!(s.parse::<DerivationPath>(), Err(error), "{s}");
+        }
+    }
+}
@@ -0,0 +1,233 @@
+use bip39::Mnemonic;
+use ed25519_dalek::SigningKey;
+use hmac::{Hmac, Mac};
+use k256::elliptic_curve::{sec1::ToEncodedPoint, PrimeField};
+use sha2::Sha512;
+
+use crate::prelude::*;
+
+/// A BIP39 mnemonic and an optional BIP39 passphrase, the secret from which
+/// all keys of a mnemonic backed `HDFactorSource` are derived.
+#[derive(Clone, PartialEq, Eq, Hash)]
+pub struct MnemonicWithPassphrase {
+    mnemonic: Mnemonic,
+    passphrase: String,
+}
+
+impl std::fmt::Debug for MnemonicWithPassphrase {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        // Never log the secret.
+        f.debug_struct("MnemonicWithPassphrase")
+            .finish_non_exhaustive()
+    }
+}
+
+impl MnemonicWithPassphrase {
+    pub fn new(mnemonic: Mnemonic, passphrase: impl AsRef<str>) -> Self {
+        Self {
+            mnemonic,
+            passphrase: passphrase.as_ref().to_owned(),
+        }
+    }
+
+    pub fn from_phrase(phrase: impl AsRef<str>, passphrase: impl AsRef<str>) -> Result<Self> {
+        Mnemonic::parse(phrase.as_ref())
+            .map(|m| Self::new(m, passphrase))
+            .map_err(|_| CommonError::InvalidMnemonic)
+    }
+
+    pub fn sample() -> Self {
+        Self::from_phrase(
+            "abandon abandon abandon abandon abandon abandon abandon abandon abandon abandon abandon about",
+            "",
+        )
+        .unwrap()
+    }
+
+    pub fn sample_other() -> Self {
+        Self::from_phrase("zoo zoo zoo zoo zoo zoo zoo zoo zoo zoo zoo wrong", "").unwrap()
+    }
+
+    fn seed(&self) -> [u8; 64] {
+        self.mnemonic.to_seed(self.passphrase.as_str())
+    }
+
+    /// Derives the public key at `derivation_path` on `curve`, using SLIP10
+    /// for Curve25519 and BIP32 for Secp256k1, which is deterministic: same
+    /// mnemonic, path and curve always yield the same key. Fails if the
+    /// entity index of `derivation_path` is not within its key space.
+    pub fn derive_public_key(
+        &self,
+        derivation_path: &DerivationPath,
+        curve: SLIP10Curve,
+    ) -> Result<PublicKey> {
+        Self::derive_public_key_from_seed(&self.seed(), derivation_path, curve)
+    }
+
+    /// Like `derive_public_key` but only computes the (expensive) seed once.
+    pub fn derive_public_keys(
+        &self,
+        derivation_paths: &IndexSet<DerivationPath>,
+        curve: SLIP10Curve,
+    ) -> Result<IndexMap<DerivationPath, PublicKey>> {
+        let seed = self.seed();
+        derivation_paths
+            .iter()
+            .map(|path| Self::derive_public_key_from_seed(&seed, path, curve).map(|k| (*path, k)))
+            .collect()
+    }
+
+    fn derive_public_key_from_seed(
+        seed: &[u8],
+        derivation_path: &DerivationPath,
+        curve: SLIP10Curve,
+    ) -> Result<PublicKey> {
+        let path = hardened_components(derivation_path)?;
+        let public_key = match curve {
+            SLIP10Curve::Curve25519 => {
+                let signing_key = slip10_ed25519(seed, &path);
+                Ed25519PublicKey::from_bytes(signing_key.verifying_key().to_bytes())
+                    .expect("Derived key is valid")
+                    .into()
+            }
+            SLIP10Curve::Secp256k1 => {
+                let secret_key = bip32_secp256k1(seed, &path);
+                let point = secret_key.public_key().to_encoded_point(true);
+                Secp256k1PublicKey::from_bytes(point.as_bytes().try_into().unwrap())
+                    .expect("Derived key is valid")
+                    .into()
+            }
+        };
+        Ok(public_key)
+    }
+}
+
+/// The hardened BIP32 components of a CAP26 path:
+/// `m/44H/1022H/<network>H/<entity kind>H/<key kind>H/<index>H`
+fn hardened_components(path: &DerivationPath) -> Result<[u32; 6]> {
+    let hardened = |c: u32| c | HARDENED_OFFSET;
+    Ok([
+        hardened(PURPOSE),
+        hardened(COIN_TYPE),
+        hardened(path.network_id.discriminant()),
+        hardened(path.entity_kind.discriminant()),
+        hardened(path.key_kind.discriminant()),
+        path.entity_index.to_hardened_u32()?,
+    ])
+}
+
+fn hmac_sha512(key: &[u8], chunks: &[&[u8]]) -> ([u8; 32], [u8; 32]) {
+    let mut mac = Hmac::<Sha512>::new_from_slice(key).expect("HMAC takes key of any size");
+    chunks.iter().for_each(|c| mac.update(c));
+    let i = mac.finalize().into_bytes();
+    let (il, ir) = i.split_at(32);
+    (il.try_into().unwrap(), ir.try_into().unwrap())
+}
+
+/// SLIP10 derivation for Ed25519, which only supports hardened children.
+fn slip10_ed25519(seed: &[u8], hardened_path: &[u32]) -> SigningKey {
+    let (mut key, mut chain_code) = hmac_sha512(b"ed25519 seed", &[seed]);
+    for component in hardened_path {
+        assert!(
+            *component >= HARDENED_OFFSET,
+            "Ed25519 requires hardened path"
+        );
+        (key, chain_code) = hmac_sha512(&chain_code, &[&[0x00], &key, &component.to_be_bytes()]);
+    }
+    SigningKey::from_bytes(&key)
+}
+
+/// BIP32 derivation for Secp256k1, we only ever use hardened children.
+fn bip32_secp256k1(seed: &[u8], hardened_path: &[u32]) -> k256::SecretKey {
+    let (key, mut chain_code) = hmac_sha512(b"Bitcoin seed", &[seed]);
+    let mut key = k256::NonZeroScalar::try_from(key.as_slice()).expect("Valid master key");
+    for component in hardened_path {
+        assert!(
+            *component >= HARDENED_OFFSET,
+            "Only hardened paths are used"
+        );
+        let (il, ir) = hmac_sha512(
+            &chain_code,
+            &[&[0x00], &key.to_repr(), &component.to_be_bytes()],
+        );
+        // The probability of an invalid child key is lower than 1 in 2^127.
+        let tweak = Option::<k256::Scalar>::from(k256::Scalar::from_repr(il.into()))
+            .expect("Valid child key");
+        key = Option::from(k256::NonZeroScalar::new(*key + tweak)).expect("Valid child key");
+        chain_code = ir;
+    }
+    k256::SecretKey::from(key)
+}
+
+#[cfg(test)]
+mod tests {
+
+    use super::*;
+
+    #[test]
+    fn slip10_ed25519_test_vector_1() {
+        let seed = hex::decode("000102030405060708090a0b0c0d0e0f").unwrap();
+        let master = slip10_ed25519(&seed, &[]);
+        assert_eq!(
+            hex::encode(master.verifying_key().to_bytes()),
+            "a4b2856bfec510abab89753fac1ac0e1112364e7d250545963f135f2a33188ed"
+        );
+        let child = slip10_ed25519(&seed, &[HARDENED_OFFSET]);
+        assert_eq!(
+            hex::encode(child.verifying_key().to_bytes()),
+            "8c8a13df77a28f3445213a0f432fde644acaa215fc72dcdf300d5efaa85d350c"
+        );
+    }
+
+    #[test]
+    fn bip32_secp256k1_test_vector_1() {
+        let seed = hex::decode("000102030405060708090a0b0c0d0e0f").unwrap();
+        let public_key = |path: &[u32]| {
+            hex::encode(
+                bip32_secp256k1(&seed, path)
+                    .public_key()
+                    .to_encoded_point(true)
+                    .as_bytes(),
+            )
+        };
+        assert_eq!(
+            public_key(&[]),
+            "0339a36013301597daef41fbe593a02cc513d0b55527ec2df1050e2e8ff49c85c2"
+        );
+        assert_eq!(
+            public_key(&[HARDENED_OFFSET]),
+            "035a784662a4a20a65bf6aab9ae98a6c068a81c52e4b032c0fb5400c706cfccc56"
+        );
+    }
+
+    #[test]
+    fn derivation_is_deterministic() {
+        let path = DerivationPath::new(
+            NetworkID::Mainnet,
+            CAP26EntityKind::Account,
+            CAP26KeyKind::TransactionSigning,
+            CAP26EntityIndex::Unsecurified(0),
+        );
+        let sut = MnemonicWithPassphrase::sample();
+        for curve in [SLIP10Curve::Curve25519, SLIP10Curve::Secp256k1] {
+            assert_eq!(
+                sut.derive_public_key(&path, curve).unwrap(),
+                sut.derive_public_key(&path, curve).unwrap()
+            );
+            assert_ne!(
+                sut.derive_public_key(&path, curve).unwrap(),
+                MnemonicWithPassphrase::sample_other()
+                    .derive_public_key(&path, curve)
+                    .unwrap()
+            );
+        }
+        let overflowing = DerivationPath {
+            entity_index: CAP26EntityIndex::Securified(u32::MAX),
+            ..path
+        };
+        assert_eq!(
+            sut.derive_public_key(&overflowing, SLIP10Curve::Curve25519),
+            Err(CommonError::CAP26EntityIndexOverflow)
+        );
+    }
+}
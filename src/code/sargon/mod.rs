@@ -1,5 +1,9 @@
 mod changed_types;
+mod mnemonic_with_passphrase;
+mod public_key;
 mod unchanged_types;
 
 pub use changed_types::*;
-pub use unchanged_types::*;
\ No newline at end of file
+pub use mnemonic_with_passphrase::*;
+pub use public_key::*;
+pub use unchanged_types::*;
@@ -0,0 +1,122 @@
+use crate::prelude::*;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub enum SLIP10Curve {
+    Curve25519,
+    Secp256k1,
+}
+
+/// An Ed25519 public key, always a valid point on the curve.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub struct Ed25519PublicKey([u8; 32]);
+impl Ed25519PublicKey {
+    pub fn from_bytes(bytes: [u8; 32]) -> Result<Self> {
+        ed25519_dalek::VerifyingKey::from_bytes(&bytes)
+            .map(|_| Self(bytes))
+            .map_err(|_| CommonError::InvalidPublicKey)
+    }
+    pub fn to_bytes(&self) -> [u8; 32] {
+        self.0
+    }
+}
+
+/// A compressed Secp256k1 public key, always a valid point on the curve.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub struct Secp256k1PublicKey([u8; 33]);
+impl Secp256k1PublicKey {
+    pub fn from_bytes(bytes: [u8; 33]) -> Result<Self> {
+        k256::PublicKey::from_sec1_bytes(&bytes)
+            .map(|_| Self(bytes))
+            .map_err(|_| CommonError::InvalidPublicKey)
+    }
+    pub fn to_bytes(&self) -> [u8; 33] {
+        self.0
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub enum PublicKey {
+    Ed25519(Ed25519PublicKey),
+    Secp256k1(Secp256k1PublicKey),
+}
+impl PublicKey {
+    pub fn curve(&self) -> SLIP10Curve {
+        match self {
+            PublicKey::Ed25519(_) => SLIP10Curve::Curve25519,
+            PublicKey::Secp256k1(_) => SLIP10Curve::Secp256k1,
+        }
+    }
+    pub fn to_bytes(&self) -> Vec<u8> {
+        match self {
+            PublicKey::Ed25519(key) => key.to_bytes().to_vec(),
+            PublicKey::Secp256k1(key) => key.to_bytes().to_vec(),
+        }
+    }
+    /// The inverse of `to_bytes`, fails if `bytes` is not a valid key on `curve`.
+    pub fn from_bytes_on_curve(bytes: &[u8], curve: SLIP10Curve) -> Result<Self> {
+        match curve {
+            SLIP10Curve::Curve25519 => bytes
+                .try_into()
+                .map_err(|_| CommonError::InvalidPublicKey)
+                .and_then(Ed25519PublicKey::from_bytes)
+                .map(Self::from),
+            SLIP10Curve::Secp256k1 => bytes
+                .try_into()
+                .map_err(|_| CommonError::InvalidPublicKey)
+                .and_then(Secp256k1PublicKey::from_bytes)
+                .map(Self::from),
+        }
+    }
+}
+impl From<Ed25519PublicKey> for PublicKey {
+    fn from(value: Ed25519PublicKey) -> Self {
+        Self::Ed25519(value)
+    }
+}
+impl From<Secp256k1PublicKey> for PublicKey {
+    fn from(value: Secp256k1PublicKey) -> Self {
+        Self::Secp256k1(value)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+
+    use super::*;
+
+    #[test]
+    fn invalid_secp256k1_bytes_is_err() {
+        assert_eq!(
+            Secp256k1PublicKey::from_bytes([0xff; 33]),
+            Err(CommonError::InvalidPublicKey)
+        );
+    }
+
+    #[test]
+    fn curve_must_match_factor_source_kind() {
+        let path = DerivationPath::new(
+            NetworkID::Mainnet,
+            CAP26EntityKind::Account,
+            CAP26KeyKind::TransactionSigning,
+            CAP26EntityIndex::Unsecurified(0),
+        );
+        let arculus = FactorSourceID::new(FactorSourceKind::Arculus, [0xcc; 32]);
+        let ed25519 = MnemonicWithPassphrase::sample()
+            .derive_public_key(&path, SLIP10Curve::Curve25519)
+            .unwrap();
+        assert_eq!(
+            HDFactorInstance::new(path, arculus, ed25519),
+            Err(CommonError::CurveDiscrepancy)
+        );
+        let secp256k1 = MnemonicWithPassphrase::sample()
+            .derive_public_key(&path, SLIP10Curve::Secp256k1)
+            .unwrap();
+        assert_eq!(
+            HDFactorInstance::new(path, arculus, secp256k1)
+                .unwrap()
+                .public_key
+                .curve(),
+            SLIP10Curve::Secp256k1
+        );
+    }
+}
@@ -1,39 +1,199 @@
 use crate::prelude::*;
 
-#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash, Error)]
+#[derive(Clone, Debug, PartialEq, Eq, Hash, Error)]
 pub enum CommonError {
-    #[error("Network Discrepancy")]
-    NetworkDiscrepancy,
+    #[error("Network Discrepancy, expected {expected:?}, found {found:?}")]
+    NetworkDiscrepancy {
+        expected: NetworkID,
+        found: NetworkID,
+    },
 
-    #[error("FactorSource Discrepancy")]
-    FactorSourceDiscrepancy,
+    #[error("FactorSource Discrepancy, expected {expected:?}, found {found:?}")]
+    FactorSourceDiscrepancy {
+        expected: FactorSourceID,
+        found: FactorSourceID,
+    },
 
-    #[error("EntityKind Discrepancy")]
-    EntityKindDiscrepancy,
+    #[error("EntityKind Discrepancy of {derivation_path} of {factor_source_id:?}, expected {expected:?}, found {found:?}")]
+    EntityKindDiscrepancy {
+        factor_source_id: FactorSourceID,
+        derivation_path: DerivationPath,
+        expected: CAP26EntityKind,
+        found: CAP26EntityKind,
+    },
 
-    #[error("KeySpace Discrepancy")]
-    KeySpaceDiscrepancy,
+    #[error("KeySpace Discrepancy of {derivation_path} of {factor_source_id:?}, expected {expected:?}, found {found:?}")]
+    KeySpaceDiscrepancy {
+        factor_source_id: FactorSourceID,
+        derivation_path: DerivationPath,
+        expected: KeySpace,
+        found: KeySpace,
+    },
 
-    #[error("KeyKind Discrepancy")]
-    KeyKindDiscrepancy,
+    #[error("KeyKind Discrepancy of {derivation_path} of {factor_source_id:?}, expected {expected:?}, found {found:?}")]
+    KeyKindDiscrepancy {
+        factor_source_id: FactorSourceID,
+        derivation_path: DerivationPath,
+        expected: CAP26KeyKind,
+        found: CAP26KeyKind,
+    },
 
     #[error("Expected Value")]
     ExpectedValue,
+
+    #[error("Invalid Mnemonic")]
+    InvalidMnemonic,
+
+    #[error("Unknown FactorSource {factor_source_id:?}")]
+    UnknownFactorSource { factor_source_id: FactorSourceID },
+
+    #[error("No Interactor For FactorSourceKind {kind:?}")]
+    NoInteractorForFactorSourceKind { kind: FactorSourceKind },
+
+    #[error("Invalid Derivation Response for {factor_source_id:?}")]
+    InvalidDerivationResponse { factor_source_id: FactorSourceID },
+
+    #[error("FactorSource {factor_source_id:?} Skipped By User")]
+    FactorSourceSkippedByUser { factor_source_id: FactorSourceID },
+
+    #[error("FactorSource {factor_source_id:?} Derivation Failed: {underlying}")]
+    FactorSourceDerivationFailed {
+        factor_source_id: FactorSourceID,
+        underlying: Box<CommonError>,
+    },
+
+    #[error("Invalid PublicKey")]
+    InvalidPublicKey,
+
+    #[error("Curve Discrepancy")]
+    CurveDiscrepancy,
+
+    #[error("CAP26EntityIndex Overflow")]
+    CAP26EntityIndexOverflow,
+
+    #[error("Non Hardened Index")]
+    NonHardenedIndex,
+
+    #[error("Invalid DerivationPath, must start with 'm'")]
+    InvalidDerivationPathPrefix,
+
+    #[error("Invalid DerivationPath, expected {expected} components, found {found}")]
+    InvalidDerivationPathLength { expected: usize, found: usize },
+
+    #[error("Invalid DerivationPath, component at position {position} is not a number")]
+    InvalidDerivationPathComponent { position: usize },
+
+    #[error("Invalid DerivationPath, component at position {position} is not hardened")]
+    NonHardenedDerivationPathComponent { position: usize },
+
+    #[error("Invalid DerivationPath, expected purpose 44, found {found}")]
+    InvalidDerivationPathPurpose { found: u32 },
+
+    #[error("Invalid DerivationPath, expected coin type 1022, found {found}")]
+    InvalidDerivationPathCoinType { found: u32 },
+
+    #[error("Unknown NetworkID {discriminant}")]
+    UnknownNetworkID { discriminant: u32 },
+
+    #[error("Unknown CAP26EntityKind {discriminant}")]
+    UnknownEntityKind { discriminant: u32 },
+
+    #[error("Unknown CAP26KeyKind {discriminant}")]
+    UnknownKeyKind { discriminant: u32 },
+
+    #[error("Unsupported cache format version {found}")]
+    UnsupportedCacheVersion { found: u32 },
+
+    #[error("Invalid cache encoding")]
+    InvalidCacheEncoding,
+
+    #[error("Failed to read from cache storage")]
+    CacheStorageReadFailed,
+
+    #[error("Failed to write to cache storage")]
+    CacheStorageWriteFailed,
+
+    #[error("Stale cache snapshot, instances it consumed have been consumed concurrently")]
+    StaleCacheSnapshot,
+
+    #[error("No DerivationTemplate matches the DerivationPath")]
+    NoMatchingDerivationTemplate,
+
+    #[error("Unknown lease, it was committed, released or reclaimed")]
+    UnknownLease,
+
+    #[error("Lease duration too long, its expiry is not representable")]
+    LeaseDurationOverflow,
+
+    #[error("FactorInstance is used in Profile")]
+    InstanceUsedInProfile,
+
+    #[error("FactorInstance {derivation_path} of {factor_source_id:?} is in the collections of another FactorSource")]
+    CollectionFactorSourceDiscrepancy {
+        factor_source_id: FactorSourceID,
+        derivation_path: DerivationPath,
+    },
+
+    #[error("FactorInstance {derivation_path} of {factor_source_id:?} is in the collections of another network")]
+    CollectionNetworkDiscrepancy {
+        factor_source_id: FactorSourceID,
+        derivation_path: DerivationPath,
+    },
+
+    #[error("FactorInstance {derivation_path} of {factor_source_id:?} is in the set of another DerivationTemplate")]
+    CollectionTemplateDiscrepancy {
+        factor_source_id: FactorSourceID,
+        derivation_path: DerivationPath,
+    },
+
+    #[error("FactorInstance {derivation_path} of {factor_source_id:?} has the same entity index as the one before it")]
+    CollectionDuplicateEntityIndex {
+        factor_source_id: FactorSourceID,
+        derivation_path: DerivationPath,
+    },
+
+    #[error("FactorInstance {derivation_path} of {factor_source_id:?} has a lower entity index than the one before it")]
+    CollectionNonIncreasingEntityIndex {
+        factor_source_id: FactorSourceID,
+        derivation_path: DerivationPath,
+    },
 }
 
 pub type Result<T, E = CommonError> = std::result::Result<T, E>;
 
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash, PartialOrd, Ord)]
+pub enum FactorSourceKind {
+    Ledger,
+    Arculus,
+    OffDeviceMnemonic,
+    Device,
+}
+impl FactorSourceKind {
+    /// The curve of all public keys derived by factor sources of this kind.
+    pub fn curve(&self) -> SLIP10Curve {
+        match self {
+            FactorSourceKind::Arculus => SLIP10Curve::Secp256k1,
+            FactorSourceKind::Ledger
+            | FactorSourceKind::OffDeviceMnemonic
+            | FactorSourceKind::Device => SLIP10Curve::Curve25519,
+        }
+    }
+}
+
 #[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
-pub struct FactorSourceID([u8; 32]);
+pub struct FactorSourceID {
+    pub kind: FactorSourceKind,
+    pub body: [u8; 32],
+}
 impl FactorSourceID {
-    pub fn new(bytes: [u8; 32]) -> Self {
-        Self(bytes)
+    pub fn new(kind: FactorSourceKind, body: [u8; 32]) -> Self {
+        Self { kind, body }
     }
     pub fn sample() -> Self {
-        Self::new([0xaa; 32])
+        Self::new(FactorSourceKind::Device, [0xaa; 32])
     }
     pub fn sample_other() -> Self {
-        Self::new([0xbb; 32])
+        Self::new(FactorSourceKind::Ledger, [0xbb; 32])
     }
 }
 
@@ -41,6 +201,27 @@ impl FactorSourceID {
 pub struct HDFactorInstance {
     pub derivation_path: DerivationPath,
     pub factor_source_id: FactorSourceID,
+    pub public_key: PublicKey,
+}
+impl HDFactorInstance {
+    /// Fails if the curve of `public_key` is not the curve used by the
+    /// kind of the factor source, or if the entity index of `derivation_path`
+    /// is not within its key space.
+    pub fn new(
+        derivation_path: DerivationPath,
+        factor_source_id: FactorSourceID,
+        public_key: PublicKey,
+    ) -> Result<Self> {
+        derivation_path.entity_index.validated()?;
+        if public_key.curve() != factor_source_id.kind.curve() {
+            return Err(CommonError::CurveDiscrepancy);
+        }
+        Ok(Self {
+            derivation_path,
+            factor_source_id,
+            public_key,
+        })
+    }
 }
 
 #[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
@@ -48,18 +229,66 @@ pub enum NetworkID {
     Mainnet,
     Testnet,
 }
+impl NetworkID {
+    pub fn all() -> IndexSet<Self> {
+        IndexSet::from_iter([Self::Mainnet, Self::Testnet])
+    }
+    /// The numeric code used in derivation paths.
+    pub fn discriminant(&self) -> u32 {
+        match self {
+            Self::Mainnet => 1,
+            Self::Testnet => 2,
+        }
+    }
+    pub fn from_discriminant(discriminant: u32) -> Result<Self> {
+        Self::all()
+            .into_iter()
+            .find(|n| n.discriminant() == discriminant)
+            .ok_or(CommonError::UnknownNetworkID { discriminant })
+    }
+}
 
 #[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
 pub enum CAP26EntityKind {
     Account,
     Identity,
 }
+impl CAP26EntityKind {
+    /// The numeric code used in derivation paths.
+    pub fn discriminant(&self) -> u32 {
+        match self {
+            Self::Account => 525,
+            Self::Identity => 618,
+        }
+    }
+    pub fn from_discriminant(discriminant: u32) -> Result<Self> {
+        [Self::Account, Self::Identity]
+            .into_iter()
+            .find(|k| k.discriminant() == discriminant)
+            .ok_or(CommonError::UnknownEntityKind { discriminant })
+    }
+}
 
 #[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
 pub enum CAP26KeyKind {
     TransactionSigning,
     AuthenticationSigning,
 }
+impl CAP26KeyKind {
+    /// The numeric code used in derivation paths.
+    pub fn discriminant(&self) -> u32 {
+        match self {
+            Self::TransactionSigning => 1460,
+            Self::AuthenticationSigning => 1678,
+        }
+    }
+    pub fn from_discriminant(discriminant: u32) -> Result<Self> {
+        [Self::TransactionSigning, Self::AuthenticationSigning]
+            .into_iter()
+            .find(|k| k.discriminant() == discriminant)
+            .ok_or(CommonError::UnknownKeyKind { discriminant })
+    }
+}
 
 #[derive(Clone, Debug, PartialEq, Eq, Hash)]
 pub struct MatrixOfFactorInstances {
@@ -67,40 +296,120 @@ pub struct MatrixOfFactorInstances {
     threshold_factors: Vec<HDFactorInstance>, // IndexSet, but need Hash.
     override_factors: Vec<HDFactorInstance>,  // IndexSet, but need Hash.
 }
+impl MatrixOfFactorInstances {
+    pub fn new(
+        threshold: u16,
+        threshold_factors: IndexSet<HDFactorInstance>,
+        override_factors: IndexSet<HDFactorInstance>,
+    ) -> Self {
+        Self {
+            threshold,
+            threshold_factors: threshold_factors.into_iter().collect(),
+            override_factors: override_factors.into_iter().collect(),
+        }
+    }
+    /// The threshold factors followed by the override factors.
+    pub fn all_factors(&self) -> IndexSet<HDFactorInstance> {
+        self.threshold_factors
+            .iter()
+            .chain(self.override_factors.iter())
+            .cloned()
+            .collect()
+    }
+}
 
 #[derive(Clone, Debug, PartialEq, Eq, Hash)]
 pub enum EntitySecurityState {
     Unsecurified(HDFactorInstance),
     Securified(MatrixOfFactorInstances),
 }
+impl EntitySecurityState {
+    /// The single instance if unsecurified, else every factor of the matrix.
+    pub fn all_factor_instances(&self) -> IndexSet<HDFactorInstance> {
+        match self {
+            Self::Unsecurified(instance) => IndexSet::from_iter([instance.clone()]),
+            Self::Securified(matrix) => matrix.all_factors(),
+        }
+    }
+}
 
 #[derive(Clone, Debug, PartialEq, Eq, Hash)]
 pub struct Account {
     entity_security_state: EntitySecurityState,
 }
+impl Account {
+    pub fn new(entity_security_state: EntitySecurityState) -> Self {
+        Self {
+            entity_security_state,
+        }
+    }
+    pub fn security_state(&self) -> EntitySecurityState {
+        self.entity_security_state.clone()
+    }
+}
 #[derive(Clone, Debug, PartialEq, Eq, Hash)]
 pub struct Persona {
     entity_security_state: EntitySecurityState,
 }
+impl Persona {
+    pub fn new(entity_security_state: EntitySecurityState) -> Self {
+        Self {
+            entity_security_state,
+        }
+    }
+    pub fn security_state(&self) -> EntitySecurityState {
+        self.entity_security_state.clone()
+    }
+}
 
 #[derive(Clone, Debug, PartialEq, Eq, Hash)]
 pub struct HDFactorSource {
     pub factor_source_id: FactorSourceID,
+    pub mnemonic_with_passphrase: MnemonicWithPassphrase,
 }
 impl HDFactorSource {
-    pub fn new(factor_source_id: FactorSourceID) -> Self {
-        Self { factor_source_id }
+    pub fn new(
+        factor_source_id: FactorSourceID,
+        mnemonic_with_passphrase: MnemonicWithPassphrase,
+    ) -> Self {
+        Self {
+            factor_source_id,
+            mnemonic_with_passphrase,
+        }
     }
     pub fn sample() -> Self {
-        Self::new(FactorSourceID::sample())
+        Self::new(FactorSourceID::sample(), MnemonicWithPassphrase::sample())
     }
     pub fn sample_other() -> Self {
-        Self::new(FactorSourceID::sample_other())
+        Self::new(
+            FactorSourceID::sample_other(),
+            MnemonicWithPassphrase::sample_other(),
+        )
+    }
+    pub fn kind(&self) -> FactorSourceKind {
+        self.factor_source_id.kind
+    }
+
+    /// Derives one `HDFactorInstance` for each path in `derivation_paths`,
+    /// fails if the entity index of any path is not within its key space.
+    pub fn derive(
+        &self,
+        derivation_paths: &IndexSet<DerivationPath>,
+    ) -> Result<IndexSet<HDFactorInstance>> {
+        self.mnemonic_with_passphrase
+            .derive_public_keys(derivation_paths, self.kind().curve())?
+            .into_iter()
+            .map(|(path, public_key)| {
+                HDFactorInstance::new(path, self.factor_source_id, public_key)
+            })
+            .collect()
     }
 }
 
 #[derive(Clone, Default, Debug, PartialEq, Eq)]
 pub struct Profile {
+    /// The factor sources the user has added, used by entities or not.
+    pub factor_sources: IndexSet<FactorSourceID>,
     pub networks: IndexMap<NetworkID, ProfileOnNetwork>,
 }
 impl Profile {
@@ -116,6 +425,27 @@ impl Profile {
             .map(|p| p.personas.clone())
             .unwrap_or_default()
     }
+    /// If `factor_source_id` is in Profile, or used by any account or persona.
+    pub fn references_factor_source(&self, factor_source_id: FactorSourceID) -> bool {
+        self.factor_sources.contains(&factor_source_id)
+            || self
+                .all_factor_instances()
+                .iter()
+                .any(|f| f.factor_source_id == factor_source_id)
+    }
+    /// Every factor instance of every account and persona on every network.
+    pub fn all_factor_instances(&self) -> IndexSet<HDFactorInstance> {
+        self.networks
+            .values()
+            .flat_map(|n| {
+                n.accounts
+                    .iter()
+                    .map(|a| a.security_state())
+                    .chain(n.personas.iter().map(|p| p.security_state()))
+            })
+            .flat_map(|s| s.all_factor_instances())
+            .collect()
+    }
 }
 
 #[derive(Clone, Debug, PartialEq, Eq)]
@@ -0,0 +1,22 @@
+use crate::prelude::*;
+
+/// An update of the cache, applied by `FactorInstancesCacheStorage::update`,
+/// with some output `T`, e.g. a report of what was updated.
+pub type CacheUpdate<T> =
+    Box<dyn FnOnce(&mut FactorInstancesForEachNetworkCache) -> Result<T> + Send>;
+
+/// Persists the `FactorInstancesForEachNetworkCache`, e.g. in memory, in a
+/// file or in the secure storage of the host.
+#[async_trait::async_trait]
+pub trait FactorInstancesCacheStorage: Send + Sync {
+    /// The persisted cache, or an empty one if nothing has been saved yet.
+    async fn load(&self) -> Result<FactorInstancesForEachNetworkCache>;
+
+    /// Replaces the persisted cache with `cache`.
+    async fn save(&self, cache: &FactorInstancesForEachNetworkCache) -> Result<()>;
+
+    /// Loads the cache, applies `update` and saves it, without any other
+    /// update happening in between, returning the output of `update`.
+    /// Nothing is saved if `update` fails.
+    async fn update<T: Send + 'static>(&self, update: CacheUpdate<T>) -> Result<T>;
+}
@@ -0,0 +1,163 @@
+use std::{
+    fs,
+    io::{ErrorKind, Write},
+    path::PathBuf,
+    sync::Mutex,
+};
+
+use crate::prelude::*;
+
+/// Keeps the cache in a file, using the binary serialization format. Writes
+/// go to a temporary file which is then atomically renamed, so that a crash
+/// never leaves a partially written cache behind.
+#[derive(Debug)]
+pub struct FileFactorInstancesCacheStorage {
+    path: PathBuf,
+    /// Serializes updates within this process.
+    lock: Mutex<()>,
+}
+impl FileFactorInstancesCacheStorage {
+    pub fn new(path: impl Into<PathBuf>) -> Self {
+        Self {
+            path: path.into(),
+            lock: Mutex::new(()),
+        }
+    }
+
+    fn read(&self) -> Result<FactorInstancesForEachNetworkCache> {
+        match fs::read(&self.path) {
+            Ok(bytes) => FactorInstancesForEachNetworkCache::from_bytes(&bytes),
+            Err(e) if e.kind() == ErrorKind::NotFound => {
+                Ok(FactorInstancesForEachNetworkCache::default())
+            }
+            Err(_) => Err(CommonError::CacheStorageReadFailed),
+        }
+    }
+
+    fn write(&self, cache: &FactorInstancesForEachNetworkCache) -> Result<()> {
+        let temporary = self.path.with_extension("tmp");
+        let write = || -> std::io::Result<()> {
+            let mut file = fs::File::create(&temporary)?;
+            file.write_all(&cache.to_bytes())?;
+            file.sync_all()?;
+            fs::rename(&temporary, &self.path)
+        };
+        write().map_err(|_| CommonError::CacheStorageWriteFailed)
+    }
+}
+
+#[async_trait::async_trait]
+impl FactorInstancesCacheStorage for FileFactorInstancesCacheStorage {
+    async fn load(&self) -> Result<FactorInstancesForEachNetworkCache> {
+        let _guard = self.lock.lock().unwrap();
+        self.read()
+    }
+
+    async fn save(&self, cache: &FactorInstancesForEachNetworkCache) -> Result<()> {
+        let _guard = self.lock.lock().unwrap();
+        self.write(cache)
+    }
+
+    async fn update<T: Send + 'static>(&self, update: CacheUpdate<T>) -> Result<T> {
+        let _guard = self.lock.lock().unwrap();
+        let mut cache = self.read()?;
+        let output = update(&mut cache)?;
+        self.write(&cache)?;
+        Ok(output)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    type Sut = FileFactorInstancesCacheStorage;
+
+    fn on_mainnet() -> FactorInstancesForSpecificNetworkCache {
+        let network_id = NetworkID::Mainnet;
+        let factor_source = HDFactorSource::sample();
+        let id = factor_source.factor_source_id;
+        let cache = FactorInstancesForSpecificNetworkCache::empty(network_id);
+        let paths = (0..3)
+            .map(|i| {
+                DerivationTemplate::AccountVeci
+                    .derivation_path(network_id, i)
+                    .unwrap()
+            })
+            .collect();
+        cache
+            .append_for_factor(
+                id,
+                ToCache(
+                    CollectionsOfFactorInstances::with_instances(
+                        network_id,
+                        id,
+                        IndexMap::from_iter([(
+                            DerivationTemplate::AccountVeci,
+                            factor_source.derive(&paths).unwrap(),
+                        )]),
+                    )
+                    .unwrap(),
+                ),
+            )
+            .unwrap();
+        cache
+    }
+
+    #[actix::test]
+    async fn load_is_empty_if_nothing_saved() {
+        let dir = tempfile::tempdir().unwrap();
+        let sut = Sut::new(dir.path().join("cache.bin"));
+        assert!(sut.load().await.unwrap().networks.is_empty());
+    }
+
+    #[actix::test]
+    async fn update_persists_across_instances() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("cache.bin");
+
+        Sut::new(&path)
+            .update(Box::new(|cache| cache.merge(on_mainnet())))
+            .await
+            .unwrap();
+
+        let loaded = Sut::new(&path).load().await.unwrap();
+        assert_eq!(
+            loaded
+                .clone_for_network(NetworkID::Mainnet)
+                .unwrap()
+                .all_factor_sources(),
+            on_mainnet().all_factor_sources()
+        );
+        assert!(!path.with_extension("tmp").exists());
+    }
+
+    #[actix::test]
+    async fn failing_update_saves_nothing() {
+        let dir = tempfile::tempdir().unwrap();
+        let sut = Sut::new(dir.path().join("cache.bin"));
+
+        let result = sut
+            .update::<()>(Box::new(|cache| {
+                cache.merge(on_mainnet())?;
+                Err(CommonError::ExpectedValue)
+            }))
+            .await;
+
+        assert_eq!(result, Err(CommonError::ExpectedValue));
+        assert!(sut.load().await.unwrap().networks.is_empty());
+    }
+
+    #[actix::test]
+    async fn corrupt_file_is_err() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("cache.bin");
+        let mut bytes = CACHE_SERIALIZATION_VERSION.to_le_bytes().to_vec();
+        bytes.push(0xff);
+        fs::write(&path, bytes).unwrap();
+        assert_eq!(
+            Sut::new(path).load().await.err(),
+            Some(CommonError::InvalidCacheEncoding)
+        );
+    }
+}
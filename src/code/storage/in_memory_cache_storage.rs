@@ -0,0 +1,36 @@
+use std::sync::Mutex;
+
+use crate::prelude::*;
+
+/// Keeps the cache in memory only, lost when dropped.
+#[derive(Debug, Default)]
+pub struct InMemoryFactorInstancesCacheStorage {
+    cache: Mutex<FactorInstancesForEachNetworkCache>,
+}
+impl InMemoryFactorInstancesCacheStorage {
+    pub fn new(cache: FactorInstancesForEachNetworkCache) -> Self {
+        Self {
+            cache: Mutex::new(cache),
+        }
+    }
+}
+
+#[async_trait::async_trait]
+impl FactorInstancesCacheStorage for InMemoryFactorInstancesCacheStorage {
+    async fn load(&self) -> Result<FactorInstancesForEachNetworkCache> {
+        Ok(self.cache.lock().unwrap().cloned_snapshot())
+    }
+
+    async fn save(&self, cache: &FactorInstancesForEachNetworkCache) -> Result<()> {
+        *self.cache.lock().unwrap() = cache.cloned_snapshot();
+        Ok(())
+    }
+
+    async fn update<T: Send + 'static>(&self, update: CacheUpdate<T>) -> Result<T> {
+        let mut guard = self.cache.lock().unwrap();
+        let mut updated = guard.cloned_snapshot();
+        let output = update(&mut updated)?;
+        *guard = updated;
+        Ok(output)
+    }
+}
@@ -0,0 +1,7 @@
+mod cache_storage;
+mod file_cache_storage;
+mod in_memory_cache_storage;
+
+pub use cache_storage::*;
+pub use file_cache_storage::*;
+pub use in_memory_cache_storage::*;
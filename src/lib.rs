@@ -9,5 +9,3 @@ pub mod prelude {
     pub(crate) use itertools::Itertools;
     pub(crate) use thiserror::Error;
 }
-
-pub(crate) use prelude::*;